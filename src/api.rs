@@ -1,22 +1,108 @@
-use reqwest::blocking::Client;
+use reqwest::blocking::{Client, RequestBuilder};
 use reqwest;
+use reqwest::StatusCode;
 use serde_json;
-use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory};
-use crate::crypto::{encrypt, hash, hash_s, decrypt, encrypt_and_hash_file};
+use fuse::{FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory};
+use crate::crypto::{unseal, hash_of_dir, is_node_hash, SealedFile, CryptoError, ProofError, KdfParams};
 use crate::fs::{ XFileAttr, File };
-use std::io::Read;
+use libc::{c_int, EACCES, EIO, ENOENT, ETIMEDOUT};
+use std::fmt;
 use base64::{encode, decode};
 use serde::{Serialize, Deserialize};
 
+#[derive(Debug)]
+pub enum ApiError {
+    // couldn't talk to the server at all
+    Transport(reqwest::Error),
+    // the server answered with a non 2xx status
+    Status(StatusCode),
+    // the response wasn't the json/base64 we expected
+    Decode(String),
+    // a blob failed to authenticate or decrypt
+    Decrypt(CryptoError),
+    // the vault has no header or root yet and has to be initialized first
+    Uninitialized,
+    // the server's answer doesn't match the hashes we trust
+    Integrity(ProofError),
+}
+
+impl ApiError {
+    // errno to hand back to the kernel for this error
+    pub fn errno(&self) -> c_int {
+        match self {
+            ApiError::Transport(e) if e.is_timeout() => ETIMEDOUT,
+            ApiError::Transport(_) => EIO,
+            ApiError::Status(StatusCode::NOT_FOUND) => ENOENT,
+            ApiError::Status(StatusCode::UNAUTHORIZED) | ApiError::Status(StatusCode::FORBIDDEN) => EACCES,
+            ApiError::Status(StatusCode::REQUEST_TIMEOUT) | ApiError::Status(StatusCode::GATEWAY_TIMEOUT) => ETIMEDOUT,
+            ApiError::Status(_) => EIO,
+            ApiError::Decode(_) | ApiError::Decrypt(_) | ApiError::Integrity(_) => EIO,
+            ApiError::Uninitialized => ENOENT,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::Transport(e) => write!(f, "transport error: {}", e),
+            ApiError::Status(status) => write!(f, "server returned {}", status),
+            ApiError::Decode(e) => write!(f, "could not decode response: {}", e),
+            ApiError::Decrypt(e) => write!(f, "could not decrypt: {}", e),
+            ApiError::Integrity(e) => write!(f, "integrity check failed: {}", e),
+            ApiError::Uninitialized => write!(f, "vault has not been initialized"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(e: reqwest::Error) -> ApiError {
+        ApiError::Transport(e)
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> ApiError {
+        ApiError::Decode(e.to_string())
+    }
+}
+
+impl From<base64::DecodeError> for ApiError {
+    fn from(e: base64::DecodeError) -> ApiError {
+        ApiError::Decode(e.to_string())
+    }
+}
+
+impl From<CryptoError> for ApiError {
+    fn from(e: CryptoError) -> ApiError {
+        ApiError::Decrypt(e)
+    }
+}
+
+impl From<ProofError> for ApiError {
+    fn from(e: ProofError) -> ApiError {
+        ApiError::Integrity(e)
+    }
+}
+
+// sends the request and returns the body, anything but 2xx is an error
+fn send(request : RequestBuilder) -> Result<Vec<u8>, ApiError> {
+    let response = request.send()?;
+    if !response.status().is_success() {
+        return Err(ApiError::Status(response.status()));
+    }
+    Ok(response.bytes()?.to_vec())
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Node {
-    hash: String,
-    metadata: String,
-    metadata_hash: String,
-    data_hash: String,
-    data : Option<String>,
-    parent_hash: String,
-    is_dir: bool,
+    pub hash: String,
+    pub metadata: String,
+    pub metadata_hash: String,
+    pub data_hash: String,
+    pub data : Option<String>,
+    pub parent_hash: String,
+    pub is_dir: bool,
 }
 
 
@@ -28,63 +114,77 @@ struct InsertPayload<'r> {
     data: Option<&'r str>,
 }
 
+// Proof of a mutation, checked with crypto::verify_tree_update before we trust it
 #[derive(Serialize, Deserialize)]
-struct InsertResponse {
-    old_tree: Vec<Node>,
-    new_tree: Vec<Node>,
-    new_top_hash: String,
+pub struct InsertResponse {
+    pub old_tree: Vec<Node>,
+    pub new_tree: Vec<Node>,
+    pub new_top_hash: String,
 }
 
-pub fn get_xattr(hash: &String, client : &mut Client, key : &Vec<u8>, server : &String) -> XFileAttr {
-    // Get file attributes of the provided file id by hash from the server
-    let url = format!("{}/node/{}", server, hash);
-    let mut response = client.get(&url).send().unwrap();
-    let mut node_json = Vec::new();
-    response.read_to_end(&mut node_json).unwrap();
-    let node: Node = serde_json::from_slice(&node_json).unwrap();
+// Unencrypted, per vault. Lets any client re-derive the key from the passphrase
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VaultHeader {
+    pub kdf: KdfParams,
+    // known plaintext sealed under the derived key, so a wrong passphrase is caught at mount
+    pub key_check: String,
+}
 
-    let nonce = vec![0; 24];
-    let decrypted = decrypt(&base64::decode(node.metadata).unwrap(), &key, &nonce);
-    let xattr: XFileAttr = serde_json::from_slice(&decrypted).unwrap();
-    xattr
+// None if the vault has not been initialized yet
+pub fn get_header(vault : &String, client : &Client, server : &String) -> Result<Option<VaultHeader>, ApiError> {
+    let url = format!("{}/header/{}", server, vault);
+    match send(client.get(&url)) {
+        Ok(body) => Ok(Some(serde_json::from_slice(&body)?)),
+        Err(ApiError::Status(StatusCode::NOT_FOUND)) => Ok(None),
+        Err(e) => Err(e),
+    }
 }
 
-pub fn set_xattr(hash: &String, file : &mut File, client : &mut Client, key : &Vec<u8>, server : &String) -> String {
-    // Set file attributes of the provided file id by hash from the server
-    let url = format!("{}/node", server);
-    
-    let encrypted = encrypt(&serde_json::to_vec(&file.xattr).unwrap(), &key, &vec![0; 24]);
-    // wasteful but ok, we need to compute the new hash to return to the caller
-    let insertpayload : InsertPayload = InsertPayload {
-        is_dir: file.xattr.attr.kind == FileType::Directory,
-        metadata: base64::encode(&encrypted).as_str(),
-        parent_hash: "",
-        data : None,
-    };
-    let hash = encrypt_and_hash_file(file, &key);
-    client.post(&url).body(encrypted).send().unwrap();
-    hash
+pub fn put_header(header : &VaultHeader, vault : &String, client : &Client, server : &String) -> Result<(), ApiError> {
+    let url = format!("{}/header/{}", server, vault);
+    let payload = serde_json::to_string(header)?;
+    send(client.post(&url).body(payload))?;
+    Ok(())
 }
 
-// Get the file data of the provided file id'd by hash from the server
-pub fn get_data(hash: &String, client : &Client, key : &Vec<u8>, server : &String) -> Vec<u8> {
-    
+// Fetch the node with the given hash as stored, i.e. still encrypted
+pub fn get_node(hash: &String, client : &Client, server : &String) -> Result<Node, ApiError> {
     let url = format!("{}/node/{}", server, hash);
-    let mut response = client.get(&url).send().unwrap();
-    let mut encrypted = Vec::new();
-    response.read_to_end(&mut encrypted).unwrap();
-    let nonce = get_nonce(hash, client, server);
-    let decrypted = decrypt(&mut encrypted, &key, &nonce);
-    decrypted
+    let node_json = send(client.get(&url))?;
+    Ok(serde_json::from_slice(&node_json)?)
 }
 
-pub fn get_nonce(hash: &String, client : &Client, server : &String) -> Vec<u8> {
-    // Get the nonce of the provided file id'd by hash from the server
-    let url = format!("{}/api/nonce/{}", server, hash);
-    let mut response = client.get(&url).send().unwrap();
-    let mut body = Vec::new();
-    response.read_to_end(&mut body).unwrap();
-    body
+// Like get_node but leaves out the (possibly large) data, data_hash is still filled in
+pub fn get_node_metadata(hash: &String, client : &Client, server : &String) -> Result<Node, ApiError> {
+    let url = format!("{}/node/{}?data=false", server, hash);
+    let node_json = send(client.get(&url))?;
+    Ok(serde_json::from_slice(&node_json)?)
+}
+
+// Get both the attributes and the data of the provided file id'd by hash in one request
+pub fn get_file(hash: &String, client : &Client, key : &Vec<u8>, server : &String) -> Result<File, ApiError> {
+    let node = get_node(hash, client, server)?;
+    let mut file = open_node(&node, key)?;
+    file.hash = hash.clone();
+    Ok(file)
+}
+
+// Decrypts a node fetched from the server into a File, data included if the node had it
+pub fn open_node(node : &Node, key : &Vec<u8>) -> Result<File, ApiError> {
+    let metadata_blob = base64::decode(&node.metadata)?;
+    let xattr: XFileAttr = serde_json::from_slice(&unseal(&metadata_blob, &key)?)?;
+    let data = match &node.data {
+        Some(data) => unseal(&base64::decode(data)?, &key)?,
+        None => Vec::new(),
+    };
+    Ok(File {
+        xattr: xattr,
+        data: data,
+        metadata_blob: metadata_blob,
+        hash: node.hash.clone(),
+        // a node without data has nothing left to download
+        loaded: node.data.is_some() || node.data_hash.is_empty(),
+    })
 }
 
 #[derive(Serialize, Deserialize)]
@@ -100,83 +200,168 @@ struct InitPayload {
     username : String,
 }
 
-pub fn new_root(xfileattr : &XFileAttr, client : &mut Client, key : &Vec<u8>, server : &String) -> String {
+#[derive(Serialize, Deserialize)]
+struct RootMetadataPayload {
+    metadata : String,
+    // the root being replaced, the server refuses if the vault has moved on from it
+    top_hash : String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RootResponse {
+    top_hash : String,
+}
+
+// The vault's current top hash, None if it has no root yet
+pub fn get_root(vault : &String, client : &Client, server : &String) -> Result<Option<String>, ApiError> {
+    let url = format!("{}/root/{}", server, vault);
+    match send(client.get(&url)) {
+        Ok(body) => {
+            let root : RootResponse = serde_json::from_slice(&body)?;
+            Ok(Some(root.top_hash))
+        }
+        Err(ApiError::Status(StatusCode::NOT_FOUND)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// metadata is the sealed root XFileAttr, returns the hash of the new (empty) root
+pub fn new_root(metadata : &Vec<u8>, vault : &String, client : &Client, server : &String) -> Result<String, ApiError> {
     let url = format!("{}/root", server);
     let insert_procedure = InitPayload {
-        metadata: base64::encode(&encrypt(&serde_json::to_vec(&xfileattr).unwrap(), &key, &vec![0;24])),
-        username: "lol".to_string(),
+        metadata: base64::encode(metadata),
+        username: vault.clone(),
     };
-    let payload = serde_json::to_string(&insert_procedure).unwrap(); // FIXME nonce
-    let mut response = client.post(&url).body(payload).send().unwrap();
-    let mut body = Vec::new();
-
-    // FIXME verify hash
-    response.read_to_end(&mut body).unwrap();
-    assert!(response.status().is_success());
-    encrypt_and_hash_file(&mut File { xattr: xfileattr.clone() , data: Vec::new() }, &key)
-    
+    let payload = serde_json::to_string(&insert_procedure)?;
+
+    let body = send(client.post(&url).body(payload))?;
+    let root : RootResponse = serde_json::from_slice(&body)?;
+    // an empty directory, so we know exactly what its hash has to be
+    let top_hash = hash_of_dir(metadata, &Vec::new());
+    if root.top_hash != top_hash {
+        return Err(ApiError::Integrity(ProofError::NodeMismatch(root.top_hash)));
+    }
+    Ok(top_hash)
 }
 
-pub fn mkdir(xfileattr : &XFileAttr, parent_hash : Option<String>, client : &mut Client, key : &Vec<u8>, server : &String) {
+// Swaps the metadata of the root at top_hash for the sealed XFileAttr in metadata, keeping
+// its children. The root has no parent to prove anything against, but with children
+// unchanged we know exactly what the new top hash has to be
+pub fn set_root_metadata(top_hash : &String, metadata : &Vec<u8>, children : &Vec<String>, vault : &String, client : &mut Client, server : &String) -> Result<String, ApiError> {
+    let url = format!("{}/root/{}", server, vault);
+    let payload = RootMetadataPayload {
+        metadata: base64::encode(metadata),
+        top_hash: top_hash.clone(),
+    };
+    let payload = serde_json::to_string(&payload)?;
+
+    let body = send(client.put(&url).body(payload))?;
+    let root : RootResponse = serde_json::from_slice(&body)?;
+    let new_top_hash = hash_of_dir(metadata, children);
+    if root.top_hash != new_top_hash {
+        return Err(ApiError::Integrity(ProofError::NodeMismatch(root.top_hash)));
+    }
+    Ok(new_top_hash)
+}
+
+// metadata is the sealed XFileAttr of the new, empty directory
+pub fn mkdir(metadata : &Vec<u8>, parent_hash : Option<String>, client : &mut Client, server : &String) -> Result<InsertResponse, ApiError> {
     let url = format!("{}/insert", server);
     let insert_procedure = InsertProcedure {
-        metadata: base64::encode(&encrypt(&serde_json::to_vec(&xfileattr).unwrap(), &key, &vec![0;24])),
+        metadata: base64::encode(metadata),
         r#type: "directory".to_string(),
         parent_hash: parent_hash.unwrap_or("".to_string()),
     };
-    let payload = serde_json::to_string(&insert_procedure).unwrap(); // FIXME nonce
-    let mut response = client.post(&url).body(payload).send().unwrap();
-    let mut body = Vec::new();
-    response.read_to_end(&mut body).unwrap();
+    let payload = serde_json::to_string(&insert_procedure)?;
+    let body = send(client.post(&url).body(payload))?;
+    let insert_response : InsertResponse = serde_json::from_slice(&body)?;
+    Ok(insert_response)
+}
+
+#[derive(Serialize, Deserialize)]
+struct MovePayload {
+    metadata : String,
+    parent_hash : String,
+    replace_hash : Option<String>,
+    // with replace_data set the node's data is swapped for data (none for an empty file)
+    // in the same mutation, otherwise the data is kept
+    replace_data : bool,
+    data : Option<String>,
+}
+
+// Moves the node to parent_hash and swaps its metadata for the given sealed XFileAttr,
+// keeping its data or children. replace_hash is a node in the new parent that is removed
+// in the same step (rename over an existing file). Renames are a single mutation so the
+// tree goes straight to one new top hash
+pub fn rename(hash : &String, metadata : &Vec<u8>, parent_hash : &String, replace_hash : Option<String>, client : &mut Client, server : &String) -> Result<InsertResponse, ApiError> {
+    let url = format!("{}/node/{}", server, hash);
+    let move_payload = MovePayload {
+        metadata: base64::encode(metadata),
+        parent_hash: parent_hash.clone(),
+        replace_hash: replace_hash,
+        replace_data: false,
+        data: None,
+    };
+    let payload = serde_json::to_string(&move_payload)?;
+    let body = send(client.put(&url).body(payload))?;
+    let insert_response : InsertResponse = serde_json::from_slice(&body)?;
+    Ok(insert_response)
 }
 
-pub fn delete(hash: &String, client : &mut Client, server : &String) -> (Vec<Node>, Vec<Node>) {
+// Swaps both the metadata and the data of the node for the sealed ones, leaving it in
+// parent_hash. Like rename this is one mutation, the node is never missing from the tree
+pub fn replace(hash : &String, sealed : &SealedFile, parent_hash : &String, client : &mut Client, server : &String) -> Result<InsertResponse, ApiError> {
     let url = format!("{}/node/{}", server, hash);
-    let mut response = client.delete(&url).send().unwrap();
-    let mut body = Vec::new();
-    assert!(response.status().is_success());
-    response.read_to_end(&mut body).unwrap();
-    let response: InsertResponse = serde_json::from_slice(&body).unwrap();
-    (response.old_tree, response.new_tree)
+    let move_payload = MovePayload {
+        metadata: base64::encode(&sealed.metadata),
+        parent_hash: parent_hash.clone(),
+        replace_hash: None,
+        replace_data: true,
+        data: sealed.data.as_ref().map(base64::encode),
+    };
+    let payload = serde_json::to_string(&move_payload)?;
+    let body = send(client.put(&url).body(payload))?;
+    let insert_response : InsertResponse = serde_json::from_slice(&body)?;
+    Ok(insert_response)
 }
 
-pub fn create(file : &File, parent_hash : &String, client : &mut Client, key : &Vec<u8>, server : &String) -> (Vec<Node>, Vec<Node>) {
-    //FIXME nonce
-    let xfileattr = file.xattr.clone();
+pub fn delete(hash: &String, client : &mut Client, server : &String) -> Result<InsertResponse, ApiError> {
+    let url = format!("{}/node/{}", server, hash);
+    let body = send(client.delete(&url))?;
+    let response: InsertResponse = serde_json::from_slice(&body)?;
+    Ok(response)
+}
 
+// uploads the blobs exactly as sealed so the server ends up with the node hash we computed
+pub fn create(sealed : &SealedFile, parent_hash : &String, client : &mut Client, server : &String) -> Result<InsertResponse, ApiError> {
     let url = format!("{}/node", server);
-    let metadata = &base64::encode(&encrypt(&serde_json::to_vec(&xfileattr).unwrap(), &key, &vec![0;24]));
+    let metadata = &base64::encode(&sealed.metadata);
     println!("metadata: {}", metadata);
     
-    let data : Option<String> = match &file.data.len() {
-        0 => None,
-        _ => Some(base64::encode(&encrypt(&file.data, &key, &vec![0;24]))),
-    };
+    let data : Option<String> = sealed.data.as_ref().map(|data| base64::encode(data));
     
     let insert_procedure = InsertPayload {
         metadata: metadata,
         is_dir: false,
         parent_hash: &parent_hash.to_string(),
-        data : None,
+        data : data.as_deref(),
     };
 
-    let payload = serde_json::to_string(&insert_procedure).unwrap(); // FIXME nonce
-    let mut response = client.post(&url).body(payload).send().unwrap();
-    let mut body = Vec::new();
-    assert!(response.status().is_success());
-    response.read_to_end(&mut body).unwrap();
-    let insert_response : InsertResponse = serde_json::from_slice(&body).unwrap();
-    (insert_response.old_tree, insert_response.new_tree)
+    let payload = serde_json::to_string(&insert_procedure)?;
+    let body = send(client.post(&url).body(payload))?;
+    let insert_response : InsertResponse = serde_json::from_slice(&body)?;
+    Ok(insert_response)
 }
 
-pub fn get_child_hashes(hash: &String, client : &Client, server : &String) -> Vec<String> { 
+pub fn get_child_hashes(hash: &String, client : &Client, server : &String) -> Result<Vec<String>, ApiError> { 
     let url = format!("{}/node/{}/children", server, hash);
-    let mut response = client.get(&url).send().unwrap();
-    let mut body = Vec::new();
-    response.read_to_end(&mut body).unwrap();
-    println!("body: {}", String::from_utf8(body.clone()).unwrap());
-    let hashes : Vec<String> = serde_json::from_slice(&body).unwrap();
-    hashes
+    let body = send(client.get(&url))?;
+    println!("body: {}", String::from_utf8_lossy(&body));
+    let hashes : Vec<String> = serde_json::from_slice(&body)?;
+    if let Some(bad) = hashes.iter().find(|hash| !is_node_hash(hash)) {
+        return Err(ApiError::Decode(format!("not a node hash in the listing of {}: {}", hash, bad)));
+    }
+    Ok(hashes)
 }
 
 
@@ -1,48 +1,170 @@
-use crypto::{symmetriccipher::{Encryptor, Decryptor}, chacha20::ChaCha20, buffer::{RefReadBuffer, RefWriteBuffer, ReadBuffer, WriteBuffer}};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, Key, aead::{Aead, KeyInit}};
+use argon2::{Argon2, Algorithm, Version, Params};
+use serde::{Serialize, Deserialize};
+use std::fmt;
 use std::io::Read;
 use crate::fs::{ File, XFileAttr };
+use crate::api;
+use crate::api::{Node, ApiError};
+use reqwest::blocking::Client;
+use std::collections::{HashMap, HashSet};
 use crypto::sha2::Sha384;
 use crypto::digest::Digest;
-use crypto::buffer::BufferResult;
+use rand::RngCore;
+use rand::rngs::OsRng;
 
-pub fn encrypt_and_hash_file(file : &mut File, key : &Vec<u8>) -> String {
-    let mut hasher_xattr = Sha384::new();
-    let mut hasher_file = Sha384::new();
+pub const NONCE_LEN: usize = 24;
+pub const TAG_LEN: usize = 16;
+pub const KEY_LEN: usize = 32;
+pub const SALT_LEN: usize = 16;
 
-    let mut plaintext_attr = serde_json::to_string(&file.xattr).unwrap();
-    let mut plaintext = file.data.clone();
+// argon2id costs for newly created vaults, existing vaults keep whatever their header says
+// 64 MiB of memory, 3 passes, single lane
+pub const DEFAULT_M_COST: u32 = 64 * 1024;
+pub const DEFAULT_T_COST: u32 = 3;
+pub const DEFAULT_P_COST: u32 = 1;
 
+// what a vault header may ask for. The header comes from the server, so without a cap it
+// could have every mount allocate gigabytes or spin for hours, and without a floor it
+// could quietly weaken the key derivation
+pub const MAX_M_COST: u32 = 1024 * 1024; // 1 GiB
+pub const MAX_T_COST: u32 = 16;
+pub const MAX_P_COST: u32 = 16;
 
-    let mut buf = plaintext_attr.as_bytes().to_vec().clone();
-    let mut ciphertext_attr = encrypt(&mut buf, &key, &generate_nonce());
-    let mut ciphertext = encrypt(&mut plaintext, &key, &generate_nonce());
-    
-    
-    hasher_xattr.input(&ciphertext_attr);
-    hasher_file.input(&ciphertext);
-    
-    let mut hash_xattr = vec![0; hasher_xattr.output_bytes()];
-    let mut hash_file = vec![0; hasher_file.output_bytes()];
+#[derive(Debug, Clone, PartialEq)]
+pub enum CryptoError {
+    // blob is too short to hold a nonce and a tag
+    Malformed,
+    // the poly1305 tag did not match: the blob was tampered with or the key is wrong
+    TagMismatch,
+    // the kdf parameters are out of range for argon2
+    BadKdfParams,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CryptoError::Malformed => write!(f, "malformed ciphertext"),
+            CryptoError::TagMismatch => write!(f, "authentication tag mismatch"),
+            CryptoError::BadKdfParams => write!(f, "invalid key derivation parameters"),
+        }
+    }
+}
+
+// everything needed to re-derive the vault key from the passphrase, stored in plain
+// text in the vault header. m_cost is in KiB
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KdfParams {
+    pub salt: String, // base64
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl KdfParams {
+    // fresh random salt with the default costs, used when initializing a vault
+    pub fn new() -> KdfParams {
+        let mut salt = vec![0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        KdfParams {
+            salt: base64::encode(&salt),
+            m_cost: DEFAULT_M_COST,
+            t_cost: DEFAULT_T_COST,
+            p_cost: DEFAULT_P_COST,
+        }
+    }
+
+    // BadKdfParams unless the costs are between our defaults and the caps above
+    // and the salt is a full SALT_LEN bytes
+    pub fn check(&self) -> Result<(), CryptoError> {
+        let salt = base64::decode(&self.salt).map_err(|_| CryptoError::BadKdfParams)?;
+        if salt.len() < SALT_LEN
+            || self.m_cost < DEFAULT_M_COST || self.m_cost > MAX_M_COST
+            || self.t_cost < DEFAULT_T_COST || self.t_cost > MAX_T_COST
+            || self.p_cost < DEFAULT_P_COST || self.p_cost > MAX_P_COST {
+            return Err(CryptoError::BadKdfParams);
+        }
+        Ok(())
+    }
+}
+
+// argon2id(passphrase, salt) -> 256 bit vault key
+pub fn derive_key(passphrase: &str, params: &KdfParams) -> Result<Vec<u8>, CryptoError> {
+    let salt = base64::decode(&params.salt).map_err(|_| CryptoError::BadKdfParams)?;
+    let argon_params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(KEY_LEN))
+        .map_err(|_| CryptoError::BadKdfParams)?;
+    let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params);
+    let mut key = vec![0u8; KEY_LEN];
+    argon.hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+        .map_err(|_| CryptoError::BadKdfParams)?;
+    Ok(key)
+}
+
+// Merkle node hashes
+//
+// A node hash commits to the node exactly as the server stores it, i.e. to the sealed
+// blobs (nonce || ciphertext || tag) and never to plaintext or a fresh encryption.
+// That way anyone holding the blobs, us included, gets the same hash every time:
+//
+//   leaf = hex(SHA-384(0x00 || SHA-384(metadata) [|| SHA-384(data)]))
+//   dir  = hex(SHA-384(0x01 || SHA-384(metadata) || child_1 || ... || child_n))
+//
+// The data digest is left out for files without data. Children are the hex hashes of
+// the directory's children sorted ascending, so the listing order doesn't matter.
+// The leading byte separates leaves from interior nodes, a file can never be passed
+// off as a directory or vice versa.
+const LEAF_TAG: u8 = 0x00;
+const DIR_TAG: u8 = 0x01;
 
-    hasher_xattr.result(&mut hash_xattr);
-    hasher_file.result(&mut hash_file);
+// a node's blobs as they are uploaded to and stored on the server
+#[derive(Clone, Debug)]
+pub struct SealedFile {
+    pub metadata: Vec<u8>,
+    pub data: Option<Vec<u8>>,
+}
+
+pub fn seal_metadata(xattr : &XFileAttr, key : &Vec<u8>) -> Vec<u8> {
+    seal(&serde_json::to_vec(xattr).unwrap(), key)
+}
+
+pub fn seal_file(file : &File, key : &Vec<u8>) -> SealedFile {
+    SealedFile {
+        metadata: seal_metadata(&file.xattr, key),
+        data: match file.data.len() {
+            0 => None,
+            _ => Some(seal(&file.data, key)),
+        },
+    }
+}
+
+pub fn hash_of_file(metadata : &Vec<u8>, data : Option<&Vec<u8>>) -> String {
+    let digest = data.filter(|data| data.len() > 0).map(|data| hash(data));
+    hash_of_file_digest(metadata, digest.as_ref())
+}
 
-    let mut node_hasher = Sha384::new();
-    node_hasher.input(&hash_xattr);
-    if file.data.len() > 0 {
-        node_hasher.input(&hash_file);
+// Same as hash_of_file when only the SHA-384 of the sealed data is at hand, which is
+// all a metadata only fetch gives us
+pub fn hash_of_file_digest(metadata : &Vec<u8>, data_digest : Option<&Vec<u8>>) -> String {
+    let mut hasher = Sha384::new();
+    hasher.input(&[LEAF_TAG]);
+    hasher.input(&hash(metadata));
+    if let Some(digest) = data_digest {
+        hasher.input(digest);
     }
+    hasher.result_str()
+}
 
-    let mut node_hash = vec![0; node_hasher.output_bytes()];
-    node_hasher.result_str()
+// true for a node hash as hash_of_file and hash_of_dir produce them: a SHA-384 digest in
+// lowercase hex. Listings come from the server, and an entry that is anything else could
+// be two children run together that still hash to the same directory
+pub fn is_node_hash(s : &str) -> bool {
+    s.len() == 96 && s.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
 }
 
-pub fn hash_of_dir(attr : &XFileAttr, children : &Vec<String>, key : &Vec<u8>) -> String {
+pub fn hash_of_dir(metadata : &Vec<u8>, children : &Vec<String>) -> String {
     let mut hasher = Sha384::new();
-    let mut plaintext = serde_json::to_string(&attr).unwrap();
-    let mut buf = plaintext.as_bytes().to_vec().clone();
-    let mut ciphertext = encrypt(&mut buf, &key, &generate_nonce());
-    hasher.input(&ciphertext);
+    hasher.input(&[DIR_TAG]);
+    hasher.input(&hash(metadata));
 
     let mut children = children.clone();
     children.sort();
@@ -50,49 +172,199 @@ pub fn hash_of_dir(attr : &XFileAttr, children : &Vec<String>, key : &Vec<u8>) -
     for child in children {
         hasher.input(child.as_bytes());
     }
-    let mut hash = vec![0; hasher.output_bytes()];
     hasher.result_str()
 }
 
-// FIXME implement
-pub fn verify_merkle_hashes() {
-    panic!("FIXME");
+#[derive(Debug, Clone)]
+pub struct HashMismatch {
+    pub hash: String,           // the hash the parent commits to
+    pub recomputed: String,     // the hash of what the server actually sent for it
+    pub parent: Option<String>, // None for the top hash
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub mismatches: Vec<HashMismatch>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+// Walks the whole tree from a trusted top hash, recomputing every node hash from the
+// blobs the server hands us, using the scheme above. A node whose content doesn't hash
+// to what its parent commits to is reported; the walk carries on below it so every bad
+// node shows up
+pub fn verify_merkle_hashes(top_hash : &String, client : &Client, server : &String) -> Result<VerifyReport, ApiError> {
+    let mut report = VerifyReport::default();
+    let mut visited : HashSet<String> = HashSet::new();
+    let mut pending : Vec<(String, Option<String>)> = vec![(top_hash.clone(), None)];
+
+    while let Some((hash, parent)) = pending.pop() {
+        if !visited.insert(hash.clone()) {
+            continue;
+        }
+        report.checked += 1;
+
+        // only the digest of a file's data goes into its hash, no need to download the data
+        let node = api::get_node_metadata(&hash, client, server)?;
+        let metadata = base64::decode(&node.metadata).unwrap_or_default();
+        let recomputed = if node.is_dir {
+            let children = api::get_child_hashes(&hash, client, server)?;
+            let recomputed = hash_of_dir(&metadata, &children);
+            for child in children {
+                pending.push((child, Some(hash.clone())));
+            }
+            recomputed
+        }
+        else {
+            let digest = match node.data_hash.as_str() {
+                "" => None,
+                data_hash => Some(from_hex(data_hash)?),
+            };
+            hash_of_file_digest(&metadata, digest.as_ref())
+        };
+
+        if recomputed != hash {
+            println!("verify: {} does not match its content (got {})", hash, recomputed);
+            report.mismatches.push(HashMismatch {
+                hash: hash,
+                recomputed: recomputed,
+                parent: parent,
+            });
+        }
+    }
+    Ok(report)
+}
+
+// a change to the children of one directory, as requested from the server
+#[derive(Debug, Clone)]
+pub enum TreeChange {
+    Insert(String), // hash of the new child
+    Remove(String), // hash of the removed child
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProofError {
+    // old and new tree differ in length, or are empty
+    Malformed,
+    // the old tree doesn't end in the top hash we trust
+    WrongRoot,
+    // an old tree node doesn't hash to its content, or isn't reachable from the root
+    BadOldNode(String),
+    // a changed directory isn't part of the old tree
+    MissingParent(String),
+    // a new tree node isn't exactly its old node with the requested change applied
+    UnexpectedChange(String),
+    // new_top_hash isn't the root of the new tree
+    WrongTopHash,
+    // a node we are about to build on doesn't hash to what we have for it
+    NodeMismatch(String),
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProofError::Malformed => write!(f, "malformed tree proof"),
+            ProofError::WrongRoot => write!(f, "old tree does not start from the trusted top hash"),
+            ProofError::BadOldNode(hash) => write!(f, "old tree node {} does not match its content", hash),
+            ProofError::MissingParent(hash) => write!(f, "changed directory {} is not in the old tree", hash),
+            ProofError::UnexpectedChange(hash) => write!(f, "new tree node replacing {} has unrequested changes", hash),
+            ProofError::WrongTopHash => write!(f, "new top hash is not the root of the new tree"),
+            ProofError::NodeMismatch(hash) => write!(f, "node {} does not match its content", hash),
+        }
+    }
+}
+
+// Checks the old_tree/new_tree proof the server returns for a mutation.
+//
+// Both trees list the directories whose hash changed, children before their parents and
+// the root last, old_tree[i] being replaced by new_tree[i]. The old tree has to hash up
+// to trusted_top, and recomputing each directory with only the requested changes (plus
+// the new hashes of its changed subdirectories) has to give exactly the new tree, ending
+// in new_top_hash. changes are keyed by the old hash of the directory they apply to.
+// children_of lists the children of an old tree directory, normally api::get_child_hashes.
+//
+// Returns old hash -> new hash for every directory in the proof
+pub fn verify_tree_update<F>(old_tree : &Vec<Node>, new_tree : &Vec<Node>, new_top_hash : &String, trusted_top : &String,
+                             changes : &Vec<(String, TreeChange)>, children_of : F) -> Result<HashMap<String, String>, ApiError>
+    where F : Fn(&String) -> Result<Vec<String>, ApiError> {
+    if old_tree.is_empty() || old_tree.len() != new_tree.len() {
+        return Err(ProofError::Malformed.into());
+    }
+    if old_tree.last().unwrap().hash != *trusted_top {
+        return Err(ProofError::WrongRoot.into());
+    }
+
+    // the old tree must be what we already trust: every node hashes to its content and
+    // hangs off a later node, so everything is committed to by the trusted root
+    let mut old_nodes : Vec<(Vec<u8>, Vec<String>)> = Vec::new();
+    for old in old_tree {
+        let metadata = base64::decode(&old.metadata).map_err(|_| ProofError::BadOldNode(old.hash.clone()))?;
+        let children = children_of(&old.hash)?;
+        if !old.is_dir || !children.iter().all(|child| is_node_hash(child))
+            || hash_of_dir(&metadata, &children) != old.hash {
+            return Err(ProofError::BadOldNode(old.hash.clone()).into());
+        }
+        old_nodes.push((metadata, children));
+    }
+    for (i, old) in old_tree.iter().enumerate().take(old_tree.len() - 1) {
+        if !old_nodes[i + 1..].iter().any(|(_, children)| children.contains(&old.hash)) {
+            return Err(ProofError::BadOldNode(old.hash.clone()).into());
+        }
+    }
+    for (parent, _) in changes {
+        if !old_tree.iter().any(|old| old.hash == *parent) {
+            return Err(ProofError::MissingParent(parent.clone()).into());
+        }
+    }
+
+    // replay the changes bottom up and compare against what the server claims
+    let mut replaced : HashMap<String, String> = HashMap::new();
+    for (i, (old, new)) in old_tree.iter().zip(new_tree.iter()).enumerate() {
+        let (metadata, old_children) = &old_nodes[i];
+        let mut children = old_children.clone();
+        for (parent, change) in changes {
+            if *parent != old.hash {
+                continue;
+            }
+            // a change that wouldn't alter the listing is not the change we asked for
+            match change {
+                TreeChange::Insert(child) if !children.contains(child) => children.push(child.clone()),
+                TreeChange::Remove(child) if children.contains(child) => children.retain(|c| c != child),
+                _ => return Err(ProofError::UnexpectedChange(old.hash.clone()).into()),
+            }
+        }
+        for child in children.iter_mut() {
+            if let Some(new_child) = replaced.get(child) {
+                *child = new_child.clone();
+            }
+        }
+
+        if new.metadata != old.metadata || !new.is_dir || hash_of_dir(metadata, &children) != new.hash {
+            return Err(ProofError::UnexpectedChange(old.hash.clone()).into());
+        }
+        replaced.insert(old.hash.clone(), new.hash.clone());
+    }
+
+    if new_tree.last().unwrap().hash != *new_top_hash {
+        return Err(ProofError::WrongTopHash.into());
+    }
+    Ok(replaced)
 }
 
 pub fn encrypt(data: &Vec<u8>, key: &Vec<u8>, nonce : &Vec<u8>) -> Vec<u8> {
-    println!("key length : {}, key : {:?}", key.len(), key);
-    let mut encryptor = Box::new(ChaCha20::new_xchacha20(&key, &nonce)) as Box<dyn Encryptor>;
-    let mut plaintext = RefReadBuffer::new(&data);
-    let mut ciphertext_out : Vec<u8> = Vec::new();
-    loop {
-        let mut buf = [0; 512];
-        let mut encrypted = RefWriteBuffer::new(&mut buf);
-        let result = encryptor.encrypt(&mut plaintext, &mut encrypted, true);
-        ciphertext_out.extend_from_slice(&encrypted.take_read_buffer().take_remaining());
-        match result.unwrap() {
-            BufferResult::BufferUnderflow => break,
-            BufferResult::BufferOverflow => continue,
-        } 
-    }
-    ciphertext_out
-}
-
-pub fn decrypt(encrypted: &Vec<u8>, key: &Vec<u8>, nonce: &Vec<u8>) -> Vec<u8> {
-    println!("key length : {}, key : {:?}", key.len(), key);
-    let mut decryptor = Box::new(ChaCha20::new_xchacha20(&key, &nonce)) as Box<dyn Decryptor>;
-    let mut ciphertext = RefReadBuffer::new(&encrypted);
-    let mut plaintext_out : Vec<u8> = Vec::new();
-    loop {
-        let mut buf = [0; 512];
-        let mut decrypted = RefWriteBuffer::new(&mut buf);
-        let result = decryptor.decrypt(&mut ciphertext, &mut decrypted, true);
-        plaintext_out.extend_from_slice(&decrypted.take_read_buffer().take_remaining());
-        match result.unwrap() {
-            BufferResult::BufferUnderflow => break,
-            BufferResult::BufferOverflow => continue,
-        } 
-    }
-    plaintext_out
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+    // only fails if the plaintext is larger than the cipher can handle (~256 GiB)
+    cipher.encrypt(XNonce::from_slice(&nonce), data.as_slice()).unwrap()
+}
+
+pub fn decrypt(encrypted: &Vec<u8>, key: &Vec<u8>, nonce: &Vec<u8>) -> Result<Vec<u8>, CryptoError> {
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+    cipher.decrypt(XNonce::from_slice(&nonce), encrypted.as_slice()).map_err(|_| CryptoError::TagMismatch)
 }
 
 pub fn hash(data: &[u8]) -> Vec<u8> {
@@ -108,9 +380,254 @@ pub fn hash_s(data: &[u8]) -> String {
     hasher.input(&data);
     hasher.result_str()
 }
+
+// inverse of result_str, for digests the server sends us as hex
+pub fn from_hex(s: &str) -> Result<Vec<u8>, CryptoError> {
+    if s.len() % 2 != 0 {
+        return Err(CryptoError::Malformed);
+    }
+    // digit by digit, from_str_radix would also take a sign
+    let digits : Vec<u8> = s.chars()
+        .map(|c| c.to_digit(16).map(|digit| digit as u8).ok_or(CryptoError::Malformed))
+        .collect::<Result<_, _>>()?;
+    Ok(digits.chunks(2).map(|pair| pair[0] << 4 | pair[1]).collect())
+}
 // we use xchacha20 so as to use a 192 bit nonce
 // otherwise our nonces may collide after sufficient file uploads
 pub fn generate_nonce() -> Vec<u8> {
-    let mut nonce = vec![0u8; 24]; // FIXME lol
+    let mut nonce = vec![0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
     nonce
 }
+
+// encrypts under a fresh nonce and prepends it, so the blob can be stored as is
+// and decrypted later without having to fetch the nonce from elsewhere
+pub fn seal(data: &Vec<u8>, key: &Vec<u8>) -> Vec<u8> {
+    let nonce = generate_nonce();
+    let mut blob = nonce.clone();
+    blob.extend_from_slice(&encrypt(data, key, &nonce));
+    blob
+}
+
+// inverse of seal: splits off the nonce, then authenticates and decrypts the rest
+pub fn unseal(blob: &Vec<u8>, key: &Vec<u8>) -> Result<Vec<u8>, CryptoError> {
+    if blob.len() < NONCE_LEN + TAG_LEN {
+        return Err(CryptoError::Malformed);
+    }
+    let (nonce, ciphertext) = blob.split_at(NONCE_LEN);
+    decrypt(&ciphertext.to_vec(), key, &nonce.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_key() -> Vec<u8> {
+        let mut key = vec![0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut key);
+        key
+    }
+
+    fn hex(bytes : &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn seal_round_trips() {
+        let key = random_key();
+        let data = b"some file contents".to_vec();
+        let blob = seal(&data, &key);
+        assert_eq!(blob.len(), NONCE_LEN + data.len() + TAG_LEN);
+        assert_eq!(unseal(&blob, &key), Ok(data));
+    }
+
+    #[test]
+    fn unseal_rejects_tampering() {
+        let key = random_key();
+        let blob = seal(&b"some file contents".to_vec(), &key);
+        // nonce, ciphertext and tag are all covered
+        for i in [0, NONCE_LEN, blob.len() - 1].iter().cloned() {
+            let mut flipped = blob.clone();
+            flipped[i] ^= 1;
+            assert_eq!(unseal(&flipped, &key), Err(CryptoError::TagMismatch));
+        }
+        assert_eq!(unseal(&blob, &random_key()), Err(CryptoError::TagMismatch));
+        assert_eq!(unseal(&blob[..NONCE_LEN + TAG_LEN - 1].to_vec(), &key), Err(CryptoError::Malformed));
+    }
+
+    #[test]
+    fn dir_hash_ignores_child_order() {
+        let metadata = b"dir".to_vec();
+        let children : Vec<String> = ["a", "b", "c"].iter().map(|name| hash_s(name.as_bytes())).collect();
+        let mut reversed = children.clone();
+        reversed.reverse();
+        assert_eq!(hash_of_dir(&metadata, &children), hash_of_dir(&metadata, &reversed));
+        assert_ne!(hash_of_dir(&metadata, &children), hash_of_dir(&metadata, &children[..2].to_vec()));
+    }
+
+    #[test]
+    fn leaf_and_dir_hashes_never_collide() {
+        let metadata = b"node".to_vec();
+        assert_ne!(hash_of_file(&metadata, None), hash_of_dir(&metadata, &Vec::new()));
+        // a file's data digest in place of a directory's child list
+        let data = b"data".to_vec();
+        let as_child = hex(&hash(&data));
+        assert_ne!(hash_of_file(&metadata, Some(&data)), hash_of_dir(&metadata, &vec![as_child]));
+    }
+
+    #[test]
+    fn file_hash_from_digest_matches() {
+        let (metadata, data) = (b"file".to_vec(), b"data".to_vec());
+        assert_eq!(hash_of_file(&metadata, Some(&data)), hash_of_file_digest(&metadata, Some(&hash(&data))));
+        assert_eq!(hash_of_file(&metadata, Some(&Vec::new())), hash_of_file_digest(&metadata, None));
+    }
+
+    #[test]
+    fn from_hex_inverts_hex_digests() {
+        let digest = hash(b"abc");
+        assert_eq!(from_hex(&hash_s(b"abc")), Ok(digest));
+        assert_eq!(from_hex("00ff7A"), Ok(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(from_hex(""), Ok(Vec::new()));
+        assert_eq!(from_hex("abc"), Err(CryptoError::Malformed));
+        assert_eq!(from_hex("zz"), Err(CryptoError::Malformed));
+        assert_eq!(from_hex("+1"), Err(CryptoError::Malformed));
+    }
+
+    fn dir_node(hash : &String, metadata : &Vec<u8>) -> Node {
+        Node {
+            hash: hash.clone(),
+            metadata: base64::encode(metadata),
+            metadata_hash: hash_s(metadata),
+            data_hash: String::new(),
+            data: None,
+            parent_hash: String::new(),
+            is_dir: true,
+        }
+    }
+
+    // root with a directory sub and a file other in it, sub holds one file.
+    // The proof is for inserting a second file into sub
+    struct Update {
+        listings : HashMap<String, Vec<String>>,
+        old_tree : Vec<Node>,
+        new_tree : Vec<Node>,
+        root : String,
+        new_root : String,
+        sub : String,
+        new_sub : String,
+        inserted : String,
+    }
+
+    fn update() -> Update {
+        let (root_meta, sub_meta) = (b"root".to_vec(), b"sub".to_vec());
+        let file = hash_of_file(&b"file".to_vec(), None);
+        let other = hash_of_file(&b"other".to_vec(), None);
+        let inserted = hash_of_file(&b"inserted".to_vec(), None);
+
+        let sub = hash_of_dir(&sub_meta, &vec![file.clone()]);
+        let root = hash_of_dir(&root_meta, &vec![sub.clone(), other.clone()]);
+        let new_sub = hash_of_dir(&sub_meta, &vec![file.clone(), inserted.clone()]);
+        let new_root = hash_of_dir(&root_meta, &vec![new_sub.clone(), other.clone()]);
+
+        let mut listings = HashMap::new();
+        listings.insert(sub.clone(), vec![file]);
+        listings.insert(root.clone(), vec![sub.clone(), other]);
+        Update {
+            listings: listings,
+            old_tree: vec![dir_node(&sub, &sub_meta), dir_node(&root, &root_meta)],
+            new_tree: vec![dir_node(&new_sub, &sub_meta), dir_node(&new_root, &root_meta)],
+            root: root,
+            new_root: new_root,
+            sub: sub,
+            new_sub: new_sub,
+            inserted: inserted,
+        }
+    }
+
+    fn verify(update : &Update, trusted_top : &String, changes : &Vec<(String, TreeChange)>) -> Result<HashMap<String, String>, ApiError> {
+        verify_tree_update(&update.old_tree, &update.new_tree, &update.new_root, trusted_top, changes,
+                           |hash| Ok(update.listings.get(hash).cloned().unwrap_or_default()))
+    }
+
+    fn proof_error(result : Result<HashMap<String, String>, ApiError>) -> ProofError {
+        match result {
+            Err(ApiError::Integrity(e)) => e,
+            Err(e) => panic!("expected a proof error, got {}", e),
+            Ok(_) => panic!("expected a proof error, the update was accepted"),
+        }
+    }
+
+    #[test]
+    fn tree_update_is_accepted() {
+        let update = update();
+        let changes = vec![(update.sub.clone(), TreeChange::Insert(update.inserted.clone()))];
+        let replaced = verify(&update, &update.root, &changes).unwrap();
+        assert_eq!(replaced.get(&update.sub), Some(&update.new_sub));
+        assert_eq!(replaced.get(&update.root), Some(&update.new_root));
+    }
+
+    #[test]
+    fn tree_update_from_wrong_root_is_rejected() {
+        let update = update();
+        let changes = vec![(update.sub.clone(), TreeChange::Insert(update.inserted.clone()))];
+        let other_root = hash_of_dir(&b"elsewhere".to_vec(), &Vec::new());
+        assert_eq!(proof_error(verify(&update, &other_root, &changes)), ProofError::WrongRoot);
+    }
+
+    #[test]
+    fn unrequested_change_is_rejected() {
+        let update = update();
+        // we asked for a removal, the server inserted instead
+        let changes = vec![(update.sub.clone(), TreeChange::Remove(update.inserted.clone()))];
+        assert_eq!(proof_error(verify(&update, &update.root, &changes)), ProofError::UnexpectedChange(update.sub.clone()));
+        // nothing asked for at all
+        assert_eq!(proof_error(verify(&update, &update.root, &Vec::new())), ProofError::UnexpectedChange(update.sub.clone()));
+    }
+
+    #[test]
+    fn merged_children_are_rejected() {
+        // the server lists two children of root as one entry, which hashes the same, and
+        // claims a removal of one of them left the tree as it was
+        let mut update = update();
+        let listing = update.listings.get(&update.root).unwrap().clone();
+        let mut sorted = listing.clone();
+        sorted.sort();
+        let root_meta = b"root".to_vec();
+        update.listings.insert(update.root.clone(), vec![sorted.concat()]);
+        update.old_tree = vec![dir_node(&update.root, &root_meta)];
+        update.new_tree = vec![dir_node(&update.root, &root_meta)];
+        update.new_root = update.root.clone();
+        let changes = vec![(update.root.clone(), TreeChange::Remove(listing[0].clone()))];
+        assert_eq!(proof_error(verify(&update, &update.root, &changes)), ProofError::BadOldNode(update.root.clone()));
+    }
+
+    #[test]
+    fn change_that_leaves_the_listing_alone_is_rejected() {
+        // inserting what is already there, or removing what isn't, changes nothing, and
+        // a server that claims the tree stayed the same must not get that accepted
+        let mut update = update();
+        update.new_tree = update.old_tree.clone();
+        update.new_root = update.root.clone();
+        let existing = update.listings.get(&update.sub).unwrap()[0].clone();
+        let changes = vec![(update.sub.clone(), TreeChange::Insert(existing))];
+        assert_eq!(proof_error(verify(&update, &update.root, &changes)), ProofError::UnexpectedChange(update.sub.clone()));
+        let changes = vec![(update.sub.clone(), TreeChange::Remove(update.inserted.clone()))];
+        assert_eq!(proof_error(verify(&update, &update.root, &changes)), ProofError::UnexpectedChange(update.sub.clone()));
+    }
+
+    #[test]
+    fn node_hashes_are_lowercase_sha384_hex() {
+        assert!(is_node_hash(&hash_s(b"abc")));
+        assert!(!is_node_hash(&hash_s(b"abc").to_uppercase()));
+        assert!(!is_node_hash(&hash_s(b"abc")[..94]));
+        assert!(!is_node_hash(&[hash_s(b"a"), hash_s(b"b")].concat()));
+    }
+
+    #[test]
+    fn wrong_new_top_hash_is_rejected() {
+        let mut update = update();
+        let changes = vec![(update.sub.clone(), TreeChange::Insert(update.inserted.clone()))];
+        update.new_root = update.root.clone();
+        assert_eq!(proof_error(verify(&update, &update.root, &changes)), ProofError::WrongTopHash);
+    }
+}
@@ -1,20 +1,58 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::ffi::OsStr;
-use std::time::{Duration, UNIX_EPOCH, SystemTime};
-use libc::{c_int, ENOENT, ENOSYS};
-use fuse::{FileType, FileAttr, Filesystem, Request, ReplyOpen, ReplyWrite, ReplyData, ReplyCreate, ReplyEntry, ReplyAttr, ReplyDirectory};
+use std::time::{Duration, Instant, UNIX_EPOCH, SystemTime};
+use libc::{c_int, R_OK, W_OK, X_OK, O_ACCMODE, O_WRONLY, O_RDWR, O_TRUNC, EACCES, EBADF, EPERM, ERANGE, E2BIG, ENOSPC, ENODATA, ENOTSUP, XATTR_CREATE, XATTR_REPLACE, ENOENT, ENOSYS, EIO, EEXIST, ENOTDIR, EISDIR, ENOTEMPTY, EINVAL, EFBIG};
+use fuse::{FileType, FileAttr, Filesystem, Request, ReplyOpen, ReplyWrite, ReplyData, ReplyCreate, ReplyEntry, ReplyAttr, ReplyDirectory, ReplyEmpty, ReplyXattr};
 use crate::api;
-use crate::api::Node;
+use crate::api::{Node, VaultHeader, InsertResponse, ApiError};
 use crypto::digest::Digest;
 use crypto::sha2::Sha384;
 use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
-use crate::crypto::{hash, encrypt_and_hash_file, hash_of_dir};
-use std::path::PathBuf;
+use crate::crypto::{seal_file, hash_of_file_digest, from_hex, seal_metadata, hash_of_file, hash_of_dir, verify_merkle_hashes, verify_tree_update, TreeChange, ProofError, derive_key, seal, unseal, KdfParams, SealedFile};
+use std::path::{Path, PathBuf};
+use rand::RngCore;
+use rand::rngs::OsRng;
 
 // cache time to live, could be set to 0 to disable caching probably
 const TTL: Duration = Duration::from_secs(1);           // 1 second
+// how long a failed lookup is trusted before asking the server again
+const NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+// the read only .q1fs directory at the root and the status file in it, never stored on
+// the server. Node inos are random, these two are kept out of their range
+const CONTROL_DIR: &str = ".q1fs";
+const CONTROL_DIR_INO: u64 = 2;
+const STATUS_FILE: &str = "status";
+const STATUS_INO: u64 = 3;
+
+// open flag telling the kernel to bypass the page cache, libc doesn't have it
+const FOPEN_DIRECT_IO: u32 = 1;
+
+// read only extended attributes every node has
+const XATTR_VIRTUAL_PREFIX: &str = "user.q1fs.";
+const XATTR_HASH: &str = "user.q1fs.hash";
+const XATTR_VERIFIED: &str = "user.q1fs.verified";
+
+// limits on extended attributes, per name, per value and for all of a node's together.
+// The total is of names and base64 values as they are stored in the metadata
+const XATTR_NAME_MAX: usize = 255;
+const XATTR_VALUE_MAX: usize = 64 * 1024;
+const XATTR_TOTAL_MAX: usize = 64 * 1024;
+
+// buffered writes of a handle that hasn't been written to for this long are committed
+// without waiting for flush/fsync/release. Each commit uploads the whole file, so a
+// handle that keeps writing is never committed halfway
+const WRITEBACK_MAX_AGE: Duration = Duration::from_secs(5);
+
+// largest file we hold, its contents are kept in memory and sealed as one blob
+const MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024; // 1 GiB
+
+// sealed into the vault header to check the passphrase at mount time
+const KEY_CHECK: &[u8] = b"q1fs key check";
+
+const SERVER_URL: &str = "http://127.0.0.1:8000/api";
 
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -22,80 +60,828 @@ pub struct XFileAttr {
     pub attr: FileAttr,
     pub file_name: String,
     pub parent_ino : u64,
+    // where a symlink points, sealed along with the rest of the metadata
+    #[serde(default)]
+    pub link_target : Option<String>,
+    // set on the extra names of a hard linked file. Such a node has no data of its own,
+    // the data and attributes live with the node of the file attr.ino
+    #[serde(default)]
+    pub hard_link : bool,
+    // user.* extended attributes, sealed and hashed with the rest of the metadata.
+    // Values are base64, serde would write bytes out as an array of numbers
+    #[serde(default)]
+    pub xattrs : BTreeMap<String, String>,
 }
 
 #[derive(Clone)]
 pub struct File {
     pub xattr: XFileAttr,
     pub data: Vec<u8>,
+    // the sealed xattr as last uploaded, directory hashes are recomputed from it
+    // whenever a child changes (see crypto.rs for the hashing scheme)
+    pub metadata_blob: Vec<u8>,
+    // where the node currently sits in the Merkle tree, changes with every upload
+    pub hash: String,
+    // false until data has been downloaded, listing a directory only fetches metadata.
+    // The data is dropped again once the file is no longer open
+    pub loaded: bool,
+}
+
+// an open file, writes through it are buffered in the cached File until committed
+struct Handle {
+    ino : u64,
+    // R_OK/W_OK as granted at open, reads and writes through the handle go by this and
+    // not by the file's mode at the time, just like an fd
+    access : i32,
+    // when the handle last wrote, None if it has nothing to commit
+    last_write : Option<Instant>,
+    // closed by the kernel, only kept around because its writes couldn't be committed yet
+    released : bool,
 }
 
 pub struct Q1FS {
-    // fs metadata
-    top_ino : u64, // tracks the highest inode number
-
-    // name -> inode -> hash -> file hashmaps
-    // effectively allowing us to retreive a file by name, inode, or hash
-    hashes : HashMap<u64, String>,
-    files : HashMap<String, File>,
-    inodes : HashMap<String, u64>,
+    // (parent, name) -> inode -> file hashmaps
+    // the inode is a node's identity, its content hash is kept on the File
+    files : HashMap<u64, File>,
+    entries : HashMap<(u64, String), u64>,
+    // extra names of hard linked files by (parent, name), their entries point at the file's ino
+    links : HashMap<(u64, String), File>,
+    // (parent ino, name) lookups that found nothing, and when
+    missing : HashMap<(u64, String), Instant>,
+    handles : HashMap<u64, Handle>,
+    next_fh : u64,
+    // files unlinked while still open, kept in files until their last handle goes away
+    orphans : HashSet<u64>,
+
+    // whether the last check of the server's tree (at mount, listing or mutation) passed
+    verified : bool,
+    // the top hash this client last adopted, if the server's root at mount was a different one
+    root_changed : Option<String>,
+    // lookups and reads answered without a round trip, and those that needed one
+    cache_hits : u64,
+    cache_misses : u64,
     
     http_client: Client,
     crypto_key: Vec<u8>,
     server_url: String,
+    vault: String,
+    // files up to this size are downloaded when opened, bigger ones on their first read or write
+    prefetch_size: u64,
 }
 
 impl Q1FS {
-    pub fn new() -> Q1FS {
-        Q1FS {
-            top_ino : 1, // 1 is reserved for root
-            
-            hashes: HashMap::new(),
+    pub fn new(passphrase : &str, vault : &str, prefetch_size : u64) -> Result<Q1FS, ApiError> {
+        let http_client = Client::new();
+        let server_url = SERVER_URL.to_string();
+        let vault = vault.to_string();
+        let header = api::get_header(&vault, &http_client, &server_url)?.ok_or(ApiError::Uninitialized)?;
+        let crypto_key = Q1FS::unlock(passphrase, &header)?;
+
+        Ok(Q1FS {
             files: HashMap::new(),
-            inodes: HashMap::new(),
+            entries: HashMap::new(),
+            links: HashMap::new(),
+            missing: HashMap::new(),
+            handles: HashMap::new(),
+            next_fh: 1,
+            orphans: HashSet::new(),
+
+            verified: false,
+            root_changed: None,
+            cache_hits: 0,
+            cache_misses: 0,
+
+            http_client: http_client,
+            crypto_key: crypto_key,
+            server_url: server_url,
+            vault: vault,
+            prefetch_size: prefetch_size,
+        })
+    }
+
+    // One time setup of a vault: writes the header with a fresh salt and creates the empty
+    // root. On a vault that is already set up this only checks the passphrase
+    pub fn create_vault(passphrase : &str, vault : &str) -> Result<(), ApiError> {
+        let client = Client::new();
+        let server = SERVER_URL.to_string();
+        let vault = vault.to_string();
 
-            http_client: Client::new(),
-            crypto_key: hash(&"hunter2".as_bytes())[..32].to_vec(), //FIXME
-            server_url: "http://127.0.0.1:8000/api".to_string(),
+        let key = match api::get_header(&vault, &client, &server)? {
+            Some(header) => Q1FS::unlock(passphrase, &header)?,
+            None => {
+                let kdf = KdfParams::new();
+                let key = derive_key(passphrase, &kdf)?;
+                let header = VaultHeader {
+                    kdf: kdf,
+                    key_check: base64::encode(&seal(&KEY_CHECK.to_vec(), &key)),
+                };
+                api::put_header(&header, &vault, &client, &server)?;
+                key
+            }
+        };
+
+        if api::get_root(&vault, &client, &server)?.is_some() {
+            println!("vault {} already has a root", vault);
+            return Ok(());
         }
+        let mut root_dir = Q1FS::root_dir(unsafe { libc::getuid() }, unsafe { libc::getgid() });
+        root_dir.metadata_blob = seal_metadata(&root_dir.xattr, &key);
+        let top_hash = api::new_root(&root_dir.metadata_blob, &vault, &client, &server)?;
+        println!("created vault {} with top hash {}", vault, top_hash);
+        Ok(())
     }
 
-    fn update_parent_hashes(&mut self, child : &File) {
-        let mut curr_file : File = child.clone();
-        loop {
-            let parent_hash = self.hashes.get(&curr_file.xattr.parent_ino);
-            match parent_hash {
-                Some(hash) => {
-                    let hash = hash.clone();
-                    let parent_file = self.files.get(&hash.clone()).unwrap().clone();
-                    let children = api::get_child_hashes(&hash.clone(), &self.http_client, &self.server_url);
-                    let new_hash = hash_of_dir(&parent_file.xattr, &children, &self.crypto_key);
-                    
-                    // update ino -> hash map
-                    self.hashes.remove(&curr_file.xattr.parent_ino);
-                    self.hashes.insert(curr_file.xattr.parent_ino, new_hash.clone());
-                    
-                    // update hash -> file map
-                    self.files.remove(&hash.clone());
-                    self.files.insert(new_hash.clone(), parent_file.clone());
-                    curr_file = parent_file;
-                },
-                None => {
-                    panic!("Parent hash not found");
+    // derives the vault key from the passphrase using the salt + costs in the vault header
+    fn unlock(passphrase : &str, header : &VaultHeader) -> Result<Vec<u8>, ApiError> {
+        header.kdf.check()?;
+        let key = derive_key(passphrase, &header.kdf)?;
+        let key_check = base64::decode(&header.key_check)?;
+        // a wrong passphrase fails here instead of on every file later on
+        unseal(&key_check, &key)?;
+        Ok(key)
+    }
+
+    // the root belongs to whoever initializes the vault
+    fn root_dir(uid : u32, gid : u32) -> File {
+        File {
+            xattr: XFileAttr {
+                attr: FileAttr {
+                    ino: 1,
+                    size: 0,
+                    blocks: 0,
+                    atime: SystemTime::now(),
+                    mtime: SystemTime::now(),
+                    ctime: SystemTime::now(),
+                    crtime: SystemTime::now(),
+                    kind: FileType::Directory,
+                    perm: 0o755,
+                    nlink: 2,
+                    uid: uid,
+                    gid: gid,
+                    rdev: 0,
+                    flags: 0,
                 },
+                file_name: "root".to_string(),
+                parent_ino: 1,
+                link_target: None,
+                hard_link: false,
+                xattrs: BTreeMap::new(),
+            },
+            data: Vec::new(),
+            metadata_blob: Vec::new(),
+            hash: String::new(),
+            loaded: true,
+        }
+    }
+
+    // Fetches the vault's top hash and checks the root node against it before trusting it
+    fn load_root(&mut self) -> Result<(), ApiError> {
+        let top_hash = api::get_root(&self.vault, &self.http_client, &self.server_url)?.ok_or(ApiError::Uninitialized)?;
+        // decrypting also authenticates the root as ours
+        let root_dir = api::get_file(&top_hash, &self.http_client, &self.crypto_key, &self.server_url)?;
+        let children = api::get_child_hashes(&top_hash, &self.http_client, &self.server_url)?;
+        if root_dir.xattr.attr.kind != FileType::Directory || root_dir.xattr.attr.ino != 1
+            || hash_of_dir(&root_dir.metadata_blob, &children) != top_hash {
+            return Err(ProofError::NodeMismatch(top_hash).into());
+        }
+        println!("mounted vault {} at top hash {}", self.vault, top_hash);
+
+        // The proofs only tie the tree to the root the server hands us at mount, an older
+        // root of ours would pass them just as well. Another mount of the vault changes the
+        // root too, so this can't refuse the mount, but it shouldn't go by unnoticed
+        if let Some(last_top) = self.last_top_hash() {
+            if last_top != top_hash {
+                println!("WARNING: vault {} was at top hash {} when last used here, the server's root has changed (or been rolled back)",
+                         self.vault, last_top);
+                self.root_changed = Some(last_top);
+            }
+        }
+        self.remember_top_hash(&top_hash);
+        self.files.insert(1, root_dir);
+        self.index_tree(children)?;
+        self.verified = true;
+        Ok(())
+    }
+
+    // Walks the whole tree below the (already checked) root so every inode in the vault
+    // maps to its node again. Each node is checked against the hash its parent lists.
+    // root_children is the root's listing, already checked against the top hash
+    fn index_tree(&mut self, root_children : Vec<String>) -> Result<(), ApiError> {
+        let mut dirs = vec![(1, root_children)];
+        while let Some((dir_ino, children)) = dirs.pop() {
+            for hash in children {
+                let (file, listing) = self.fetch_child(dir_ino, &hash)?;
+                if let Some(listing) = listing {
+                    dirs.push((file.xattr.attr.ino, listing));
+                }
+                self.cache_node(dir_ino, file)?;
+            }
+        }
+        println!("indexed {} inodes", self.files.len());
+        Ok(())
+    }
+
+    // Fetches the metadata of the node at hash without its data. Files are checked against
+    // hash right away using the data digest, directories need their listing for that
+    fn fetch_metadata(&self, hash : &String) -> Result<File, ApiError> {
+        let node = api::get_node_metadata(hash, &self.http_client, &self.server_url)?;
+        let mut file = api::open_node(&node, &self.crypto_key)?;
+        file.hash = hash.clone();
+        if file.xattr.attr.kind != FileType::Directory {
+            let digest = match node.data_hash.as_str() {
+                "" => None,
+                data_hash => Some(from_hex(data_hash)?),
+            };
+            if hash_of_file_digest(&file.metadata_blob, digest.as_ref()) != *hash {
+                return Err(ProofError::NodeMismatch(hash.clone()).into());
+            }
+        }
+        Ok(file)
+    }
+
+    // Fetches the metadata of the child at hash of the directory parent and checks it
+    // before it is cached: a directory against its own listing, a file against its data
+    // digest, and either has to name parent as the directory it is in. A directory's
+    // checked listing comes back with it
+    fn fetch_child(&self, parent : u64, hash : &String) -> Result<(File, Option<Vec<String>>), ApiError> {
+        let file = self.fetch_metadata(hash)?;
+        let mut listing = None;
+        if file.xattr.attr.kind == FileType::Directory {
+            let children = api::get_child_hashes(hash, &self.http_client, &self.server_url)?;
+            if hash_of_dir(&file.metadata_blob, &children) != *hash {
+                return Err(ProofError::NodeMismatch(hash.clone()).into());
+            }
+            listing = Some(children);
+        }
+        if file.xattr.parent_ino != parent {
+            return Err(ProofError::NodeMismatch(hash.clone()).into());
+        }
+        Ok((file, listing))
+    }
+
+    // Adds a freshly fetched child of parent to the maps, only its metadata so far
+    fn cache_node(&mut self, parent : u64, file : File) -> Result<(), ApiError> {
+        let ino = file.xattr.attr.ino;
+        if file.xattr.hard_link {
+            self.entries.insert((parent, file.xattr.file_name.clone()), ino);
+            self.links.insert((parent, file.xattr.file_name.clone()), file);
+            return Ok(());
+        }
+        self.entries.insert((parent, file.xattr.file_name.clone()), ino);
+        self.files.insert(ino, file);
+        Ok(())
+    }
+
+    // Downloads the data of the file at ino if we only have its metadata so far
+    fn load_data(&mut self, ino : u64) -> Result<(), ApiError> {
+        let file = match self.files.get(&ino) {
+            Some(file) if file.xattr.attr.kind != FileType::Directory => file,
+            _ => return Ok(()),
+        };
+        if file.loaded {
+            self.cache_hits += 1;
+            return Ok(());
+        }
+        self.cache_misses += 1;
+        println!("downloading data of {}", ino);
+        let node = api::get_node(&file.hash, &self.http_client, &self.server_url)?;
+        let blob = match node.data {
+            Some(data) => Some(base64::decode(data)?),
+            None => None,
+        };
+        if hash_of_file(&file.metadata_blob, blob.as_ref()) != file.hash {
+            let hash = file.hash.clone();
+            self.verified = false;
+            return Err(ProofError::NodeMismatch(hash).into());
+        }
+        let data = match blob {
+            Some(blob) => unseal(&blob, &self.crypto_key)?,
+            None => Vec::new(),
+        };
+        let file = self.files.get_mut(&ino).unwrap();
+        file.data = data;
+        file.loaded = true;
+        Ok(())
+    }
+
+    // A random inode number for a new node. Random rather than counted so that other
+    // mounts of the vault don't hand out the same numbers; 0 to 3 are reserved
+    fn new_ino(&self) -> u64 {
+        loop {
+            let ino = OsRng.next_u64();
+            if ino > STATUS_INO && !self.files.contains_key(&ino) {
+                return ino;
+            }
+        }
+    }
+
+    // Checks the server's proof for a mutation against the top hash we trust and only if
+    // it holds adopts the new hashes of every directory on the changed path
+    fn apply_update(&mut self, response : InsertResponse, changes : Vec<(String, TreeChange)>) -> Result<(), ApiError> {
+        let trusted_top = self.hash_of(1).unwrap();
+        let (client, server) = (&self.http_client, &self.server_url);
+        let replaced = match verify_tree_update(&response.old_tree, &response.new_tree, &response.new_top_hash, &trusted_top, &changes,
+                                                |hash| api::get_child_hashes(hash, client, server)) {
+            Ok(replaced) => replaced,
+            Err(e) => {
+                if let ApiError::Integrity(_) = e {
+                    self.verified = false;
+                }
+                return Err(e);
+            }
+        };
+        self.verified = true;
+        // directories we never listed aren't cached, nothing to update
+        for dir in self.files.values_mut() {
+            if let Some(new_hash) = replaced.get(&dir.hash) {
+                dir.hash = new_hash.clone();
+            }
+        }
+        println!("adopted top hash {}", response.new_top_hash);
+        self.remember_top_hash(&response.new_top_hash);
+        Ok(())
+    }
+
+    // where the last adopted top hash of the vault is kept between mounts
+    fn top_hash_path(&self) -> Option<PathBuf> {
+        let home = env::var("HOME").ok()?;
+        Some(Path::new(&home).join(".q1fs").join(format!("{}.top", self.vault)))
+    }
+
+    fn last_top_hash(&self) -> Option<String> {
+        let path = self.top_hash_path()?;
+        std::fs::read_to_string(path).ok().map(|hash| hash.trim().to_string())
+    }
+
+    // failing to save it only weakens the check at the next mount, so it's not an error
+    fn remember_top_hash(&self, top_hash : &String) {
+        let path = match self.top_hash_path() {
+            Some(path) => path,
+            None => return,
+        };
+        let saved = path.parent().map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, top_hash));
+        if let Err(e) = saved {
+            println!("could not save top hash to {}: {}", path.display(), e);
+        }
+    }
+
+    // Uploads a new node under parent_hash and checks the server's proof for it
+    fn insert_node(&mut self, sealed : &SealedFile, hash : &String, parent_hash : &String) -> Result<(), ApiError> {
+        let response = api::create(sealed, parent_hash, &mut self.http_client, &self.server_url)?;
+        self.apply_update(response, vec![(parent_hash.clone(), TreeChange::Insert(hash.clone()))])
+    }
+
+    // current content hash of the cached node at ino
+    fn hash_of(&self, ino : u64) -> Option<String> {
+        self.files.get(&ino).map(|file| file.hash.clone())
+    }
+
+    // ino and hash of the cached child called name in the directory parent
+    fn find_child(&self, parent : u64, name : &str) -> Option<(u64, String)> {
+        let ino = *self.entries.get(&(parent, name.to_string()))?;
+        self.hash_of(ino).map(|hash| (ino, hash))
+    }
+
+    // Deletes the node at ino from the server, checks the proof and drops it from the local maps
+    fn remove_node(&mut self, ino : u64) -> Result<(), ApiError> {
+        // an open file stays readable after the unlink, so it needs its data while it still can
+        if self.handles.values().any(|handle| handle.ino == ino) {
+            self.load_data(ino)?;
+        }
+        let file = self.files.get(&ino).unwrap().clone();
+        let parent_hash = self.hash_of(file.xattr.parent_ino).unwrap();
+        let response = api::delete(&file.hash, &mut self.http_client, &self.server_url)?;
+        self.apply_update(response, vec![(parent_hash, TreeChange::Remove(file.hash.clone()))])?;
+
+        self.drop_node(ino);
+        self.entries.remove(&(file.xattr.parent_ino, file.xattr.file_name));
+        Ok(())
+    }
+
+    // Forgets the file at ino once it is gone from the server, or marks it an orphan
+    // if it is still open. Its entries are up to the caller
+    fn drop_node(&mut self, ino : u64) {
+        if self.handles.values().any(|handle| handle.ino == ino) {
+            self.orphans.insert(ino);
+        }
+        else {
+            self.files.remove(&ino);
+        }
+    }
+
+    // Once nothing has the file at ino open anymore its data is dropped from the cache,
+    // the next open downloads it again. An orphan goes away entirely
+    fn forget_closed(&mut self, ino : u64) {
+        if self.handles.values().any(|handle| handle.ino == ino) {
+            return;
+        }
+        if self.orphans.remove(&ino) {
+            self.files.remove(&ino);
+            return;
+        }
+        if let Some(file) = self.files.get_mut(&ino) {
+            if file.xattr.attr.kind != FileType::Directory && !file.data.is_empty() {
+                file.data = Vec::new();
+                file.loaded = false;
+            }
+        }
+    }
+
+    // true if ancestor is ino itself or one of the directories above it
+    fn is_ancestor(&self, ancestor : u64, ino : u64) -> bool {
+        let mut curr = ino;
+        loop {
+            if curr == ancestor {
+                return true;
+            }
+            if curr == 1 {
+                return false;
+            }
+            match self.files.get(&curr) {
+                Some(file) => curr = file.xattr.parent_ino,
+                None => return false,
+            }
+        }
+    }
+
+    // The hash file will have once its metadata is swapped for new_metadata, keeping its
+    // data or children as the server stores them. What the server has is checked against
+    // old_hash first so we never build on content we don't trust
+    fn hash_with_metadata(&self, old_hash : &String, file : &File, new_metadata : &Vec<u8>) -> Result<String, ApiError> {
+        if file.xattr.attr.kind == FileType::Directory {
+            let children = api::get_child_hashes(old_hash, &self.http_client, &self.server_url)?;
+            if hash_of_dir(&file.metadata_blob, &children) != *old_hash {
+                return Err(ProofError::NodeMismatch(old_hash.clone()).into());
+            }
+            Ok(hash_of_dir(new_metadata, &children))
+        }
+        else {
+            // only the digest of the data goes into the hash, no need to download it
+            let node = api::get_node_metadata(old_hash, &self.http_client, &self.server_url)?;
+            let digest = match node.data_hash.as_str() {
+                "" => None,
+                data_hash => Some(from_hex(data_hash)?),
+            };
+            if hash_of_file_digest(&file.metadata_blob, digest.as_ref()) != *old_hash {
+                return Err(ProofError::NodeMismatch(old_hash.clone()).into());
+            }
+            Ok(hash_of_file_digest(new_metadata, digest.as_ref()))
+        }
+    }
+
+    // Sends the node of old_file to xattr.parent_ino under the name and metadata in xattr in
+    // one server mutation, replacing the node target_hash if given. Returns the new sealed
+    // metadata and hash, the local maps are left to the caller
+    fn relocate(&mut self, old_file : &File, xattr : &XFileAttr, target_hash : Option<String>) -> Result<(Vec<u8>, String), ApiError> {
+        let old_hash = &old_file.hash;
+        let old_parent_hash = self.hash_of(old_file.xattr.parent_ino).unwrap();
+        let new_parent_hash = self.hash_of(xattr.parent_ino).unwrap();
+
+        let new_metadata = seal_metadata(xattr, &self.crypto_key);
+        let new_hash = self.hash_with_metadata(old_hash, old_file, &new_metadata)?;
+
+        let response = api::rename(old_hash, &new_metadata, &new_parent_hash, target_hash.clone(), &mut self.http_client, &self.server_url)?;
+
+        // when both parents are the same directory all of these apply to it
+        let mut changes = vec![(old_parent_hash, TreeChange::Remove(old_hash.clone()))];
+        if let Some(target_hash) = &target_hash {
+            changes.push((new_parent_hash.clone(), TreeChange::Remove(target_hash.clone())));
+        }
+        changes.push((new_parent_hash, TreeChange::Insert(new_hash.clone())));
+        self.apply_update(response, changes)?;
+        Ok((new_metadata, new_hash))
+    }
+
+    // Moves the node at ino to moved.xattr.parent_ino under its new name, replacing the
+    // node target_hash if given. Whatever was cached for the target is up to the caller.
+    // The root can't move, for it this only updates the metadata
+    fn move_node(&mut self, ino : u64, moved : &mut File, target_hash : Option<String>) -> Result<(), ApiError> {
+        if ino == 1 {
+            return self.update_root(moved);
+        }
+        // unlinked while still open, it only lives on in the cache
+        if self.orphans.contains(&ino) {
+            self.files.insert(ino, moved.clone());
+            return Ok(());
+        }
+        // moved comes from the cache, so its size and mtime may already be those of
+        // buffered writes. Those have to be on the server before metadata saying so is
+        self.commit_ino(ino)?;
+        let old_file = self.files.get(&ino).unwrap().clone();
+        let (new_metadata, new_hash) = self.relocate(&old_file, &moved.xattr, target_hash)?;
+
+        self.entries.remove(&(old_file.xattr.parent_ino, old_file.xattr.file_name.clone()));
+        moved.metadata_blob = new_metadata;
+        moved.hash = new_hash;
+        self.missing.remove(&(moved.xattr.parent_ino, moved.xattr.file_name.clone()));
+        self.entries.insert((moved.xattr.parent_ino, moved.xattr.file_name.clone()), ino);
+        self.files.insert(ino, moved.clone());
+        Ok(())
+    }
+
+    // Replaces the root's metadata with that of root, its children stay as they are
+    fn update_root(&mut self, root : &mut File) -> Result<(), ApiError> {
+        let top_hash = self.hash_of(1).unwrap();
+        let children = api::get_child_hashes(&top_hash, &self.http_client, &self.server_url)?;
+        let old_metadata = &self.files.get(&1).unwrap().metadata_blob;
+        if hash_of_dir(old_metadata, &children) != top_hash {
+            self.verified = false;
+            return Err(ProofError::NodeMismatch(top_hash).into());
+        }
+
+        let new_metadata = seal_metadata(&root.xattr, &self.crypto_key);
+        let new_top_hash = match api::set_root_metadata(&top_hash, &new_metadata, &children, &self.vault, &mut self.http_client, &self.server_url) {
+            Ok(new_top_hash) => new_top_hash,
+            Err(e) => {
+                if let ApiError::Integrity(_) = e {
+                    self.verified = false;
+                }
+                return Err(e);
+            }
+        };
+        println!("adopted top hash {}", new_top_hash);
+        self.remember_top_hash(&new_top_hash);
+        root.metadata_blob = new_metadata;
+        root.hash = new_top_hash;
+        self.files.insert(1, root.clone());
+        Ok(())
+    }
+
+    // Moves the extra hard link at (parent, name) to (new_parent, new_name), replacing
+    // the node target_hash if given
+    fn move_link(&mut self, parent : u64, name : &str, new_parent : u64, new_name : &str, target_hash : Option<String>) -> Result<(), ApiError> {
+        let key = (parent, name.to_string());
+        let mut link = self.links.get(&key).unwrap().clone();
+        let mut xattr = link.xattr.clone();
+        xattr.file_name = new_name.to_string();
+        xattr.parent_ino = new_parent;
+        let (new_metadata, new_hash) = self.relocate(&link, &xattr, target_hash)?;
+
+        self.links.remove(&key);
+        self.entries.remove(&key);
+        link.xattr = xattr;
+        link.metadata_blob = new_metadata;
+        link.hash = new_hash;
+        let new_key = (new_parent, new_name.to_string());
+        self.missing.remove(&new_key);
+        self.entries.insert(new_key.clone(), link.xattr.attr.ino);
+        self.links.insert(new_key, link);
+        Ok(())
+    }
+
+    // Removes the name (parent, name) of a file. The data only goes away with the last
+    // name, until then the file's node moves over to one of the names it has left
+    fn unlink_entry(&mut self, parent : u64, name : &str) -> Result<(), ApiError> {
+        let key = (parent, name.to_string());
+        if let Some(link) = self.links.get(&key).cloned() {
+            let parent_hash = self.hash_of(parent).unwrap();
+            let response = api::delete(&link.hash, &mut self.http_client, &self.server_url)?;
+            self.apply_update(response, vec![(parent_hash, TreeChange::Remove(link.hash.clone()))])?;
+            self.links.remove(&key);
+            self.entries.remove(&key);
+            return Ok(());
+        }
+
+        let ino = *self.entries.get(&key).unwrap();
+        let other_name = self.links.iter()
+            .find(|(_, link)| link.xattr.attr.ino == ino)
+            .map(|(other_key, link)| (other_key.clone(), link.hash.clone()));
+        match other_name {
+            Some((other_key, link_hash)) => {
+                let mut moved = self.files.get(&ino).unwrap().clone();
+                moved.xattr.file_name = other_key.1.clone();
+                moved.xattr.parent_ino = other_key.0;
+                moved.xattr.attr.ctime = SystemTime::now();
+                self.move_node(ino, &mut moved, Some(link_hash))?;
+                self.links.remove(&other_key);
+                Ok(())
             }
-            if curr_file.xattr.parent_ino == 1 {
-                break;
+            None => self.remove_node(ino),
+        }
+    }
+
+    // attr of ino as we report it, with the link count filled in: 2 plus the number of
+    // subdirectories for a directory, the number of names for anything else
+    fn attr_of(&self, ino : u64) -> Option<FileAttr> {
+        if ino == CONTROL_DIR_INO || ino == STATUS_INO {
+            return Some(self.control_attr(ino));
+        }
+        let file = self.files.get(&ino)?;
+        let mut attr = file.xattr.attr.clone();
+        attr.nlink = if attr.kind == FileType::Directory {
+            2 + self.files.values()
+                .filter(|f| f.xattr.parent_ino == ino && f.xattr.attr.ino != ino && f.xattr.attr.kind == FileType::Directory)
+                .count() as u32
+        }
+        else if self.orphans.contains(&ino) {
+            0
+        }
+        else {
+            1 + self.links.values().filter(|link| link.xattr.attr.ino == ino).count() as u32
+        };
+        Some(attr)
+    }
+
+    // attr of .q1fs or the status file in it, they belong to the owner of the root
+    fn control_attr(&self, ino : u64) -> FileAttr {
+        let root = &self.files.get(&1).unwrap().xattr.attr;
+        let (kind, perm, size, nlink) = match ino {
+            CONTROL_DIR_INO => (FileType::Directory, 0o555, 0, 2),
+            _ => (FileType::RegularFile, 0o444, self.status().len() as u64, 1),
+        };
+        FileAttr {
+            ino: ino,
+            size: size,
+            blocks: 0,
+            atime: SystemTime::now(),
+            mtime: SystemTime::now(),
+            ctime: SystemTime::now(),
+            crtime: root.crtime,
+            kind: kind,
+            perm: perm,
+            nlink: nlink,
+            uid: root.uid,
+            gid: root.gid,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    // contents of .q1fs/status, made up fresh on every read
+    fn status(&self) -> Vec<u8> {
+        let pending : HashSet<u64> = self.handles.values()
+            .filter(|handle| handle.last_write.is_some())
+            .map(|handle| handle.ino)
+            .collect();
+        let lookups = self.cache_hits + self.cache_misses;
+        let hit_rate = match lookups {
+            0 => 0.0,
+            _ => 100.0 * self.cache_hits as f64 / lookups as f64,
+        };
+        let root_changed = match &self.root_changed {
+            Some(last_top) => format!("yes, was {}", last_top),
+            None => "no".to_string(),
+        };
+        format!("vault: {}\ntop hash: {}\nverified: {}\nroot changed since last use: {}\npending uploads: {}\ncache hits: {} of {} ({:.1}%)\n",
+                self.vault, self.hash_of(1).unwrap_or_default(), self.verified, root_changed, pending.len(),
+                self.cache_hits, lookups, hit_rate).into_bytes()
+    }
+
+    // Replaces the node at ino on the server with a freshly sealed copy of file (data
+    // included, so it has to be loaded) and updates its cached copy. Returns the new hash
+    fn reupload(&mut self, ino : u64, file : &mut File) -> Result<String, ApiError> {
+        // unlinked while still open, there is nothing on the server to replace and the
+        // writes only ever live in the cache
+        if !self.orphans.contains(&ino) {
+            let old_hash = self.hash_of(ino).unwrap();
+            let parent_hash = self.hash_of(file.xattr.parent_ino).unwrap();
+            let sealed = seal_file(file, &self.crypto_key);
+            let new_hash = hash_of_file(&sealed.metadata, sealed.data.as_ref());
+
+            let response = api::replace(&old_hash, &sealed, &parent_hash, &mut self.http_client, &self.server_url)?;
+            self.apply_update(response, vec![(parent_hash.clone(), TreeChange::Remove(old_hash)),
+                                             (parent_hash, TreeChange::Insert(new_hash.clone()))])?;
+            file.metadata_blob = sealed.metadata;
+            file.hash = new_hash;
+        }
+        self.files.insert(ino, file.clone());
+
+        // whatever the handles had buffered went up with it
+        for handle in self.handles.values_mut().filter(|handle| handle.ino == ino) {
+            handle.last_write = None;
+        }
+        Ok(file.hash.clone())
+    }
+
+    // EACCES unless the caller may access ino as mask (R_OK/W_OK/X_OK) says
+    fn check_access(&self, req : &Request, ino : u64, mask : i32) -> Result<(), c_int> {
+        match self.attr_of(ino) {
+            Some(attr) if may_access(&attr, req.uid(), req.gid(), mask) => Ok(()),
+            Some(_) => Err(EACCES),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn open_handle(&mut self, ino : u64, access : i32) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(fh, Handle { ino: ino, access: access, last_write: None, released: false });
+        fh
+    }
+
+    // EBADF unless fh is an open handle of ino that was opened for mask (R_OK/W_OK)
+    fn check_handle(&self, fh : u64, ino : u64, mask : i32) -> Result<(), c_int> {
+        match self.handles.get(&fh) {
+            Some(handle) if handle.ino == ino && handle.access & mask == mask => Ok(()),
+            _ => Err(EBADF),
+        }
+    }
+
+    // Uploads the file behind fh if there are buffered writes for it
+    fn commit(&mut self, fh : u64) -> Result<(), ApiError> {
+        let ino = match self.handles.get(&fh) {
+            Some(handle) if handle.last_write.is_some() => handle.ino,
+            _ => return Ok(()),
+        };
+        let mut file = match self.files.get(&ino) {
+            Some(file) => file.clone(),
+            None => return Ok(()),
+        };
+        println!("committing {} (fh {})", ino, fh);
+        self.reupload(ino, &mut file)?;
+        Ok(())
+    }
+
+    // Uploads the file at ino if any of its handles has buffered writes, one upload
+    // takes care of all of them
+    fn commit_ino(&mut self, ino : u64) -> Result<(), ApiError> {
+        let dirty = self.handles.iter()
+            .find(|(_, handle)| handle.ino == ino && handle.last_write.is_some())
+            .map(|(fh, _)| *fh);
+        match dirty {
+            Some(fh) => self.commit(fh),
+            None => Ok(()),
+        }
+    }
+
+    // Commits every handle with dirty data that hasn't been written to for WRITEBACK_MAX_AGE.
+    // There is no timer, so this runs at the start of the common callbacks instead.
+    // A failed commit stays dirty and is retried once the age has passed again
+    fn commit_expired(&mut self) {
+        let expired : Vec<u64> = self.handles.iter()
+            .filter(|(_, handle)| handle.last_write.map_or(false, |since| since.elapsed() >= WRITEBACK_MAX_AGE))
+            .map(|(fh, _)| *fh)
+            .collect();
+        for fh in expired {
+            if let Err(e) = self.commit(fh) {
+                println!("could not commit fh {}: {}", fh, e);
+                if let Some(handle) = self.handles.get_mut(&fh) {
+                    handle.last_write = Some(Instant::now());
+                }
             }
         }
+        // released handles that have been committed now, at last
+        let closed : Vec<u64> = self.handles.values()
+            .filter(|handle| handle.released && handle.last_write.is_none())
+            .map(|handle| handle.ino)
+            .collect();
+        self.handles.retain(|_, handle| !handle.released || handle.last_write.is_some());
+        for ino in closed {
+            self.forget_closed(ino);
+        }
     }
 }
 
+// The permission bits of a mode from the kernel. The caller's umask has already been
+// applied to it by the kernel, the fuse abi we speak doesn't send the umask along
+fn mode_to_perm(mode : u32) -> u16 {
+    (mode & 0o7777) as u16
+}
+
+// .q1fs at the root is ours, nothing can be created under that name
+fn is_reserved(parent : u64, name : &str) -> bool {
+    parent == 1 && name == CONTROL_DIR
+}
+
+// Classic unix permission check against the owner, group or other bits of attr.
+// Supplementary groups aren't passed on by fuse, so only the primary gid counts
+fn may_access(attr : &FileAttr, uid : u32, gid : u32, mask : i32) -> bool {
+    if uid == 0 {
+        // root may do anything, except execute what nobody can
+        return mask & X_OK == 0 || attr.kind == FileType::Directory || attr.perm & 0o111 != 0;
+    }
+    let bits = if uid == attr.uid {
+        (attr.perm >> 6) & 0o7
+    }
+    else if gid == attr.gid {
+        (attr.perm >> 3) & 0o7
+    }
+    else {
+        attr.perm & 0o7
+    };
+    (mask as u16) & !bits == 0
+}
+
+// what open(2) flags ask for in terms of access(2) bits
+fn open_mask(flags : u32) -> i32 {
+    let mut mask = match flags as i32 & O_ACCMODE {
+        O_WRONLY => W_OK,
+        O_RDWR => R_OK | W_OK,
+        _ => R_OK,
+    };
+    if flags as i32 & O_TRUNC != 0 {
+        mask |= W_OK;
+    }
+    mask
+}
+
 impl Filesystem for Q1FS {
     
 
     fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
         println!("readdir: ino: {}, offset: {}", ino, offset);
+        self.commit_expired();
         // Directories always contain . and .. offset magic is unclear, but saw it in the example
         
         if offset <= 1 {
@@ -104,36 +890,89 @@ impl Filesystem for Q1FS {
             }
             reply.add(ino, 2, FileType::Directory, "..");
         }
+        if ino == CONTROL_DIR_INO {
+            if offset <= 2 {
+                reply.add(STATUS_INO, 3, FileType::RegularFile, STATUS_FILE);
+            }
+            reply.ok();
+            return;
+        }
         
-        let dir_hash = self.hashes.get(&ino);
+        let dir_hash = self.hash_of(ino);
         match dir_hash {
             Some(hash) => {
-                let file = self.files.get(hash).unwrap();
-                let saved_file = file.clone();
+                let file = self.files.get(&ino).unwrap();
                 if file.xattr.attr.kind != FileType::Directory {
                     println!("readdir: not a directory");
                     reply.error(-1); // todo: replace with "not a directory" error
                     return;
                 }
 
-                let hashes_of_children = api::get_child_hashes(&hash, &mut self.http_client, &self.server_url);
+                let mut hashes_of_children = match api::get_child_hashes(&hash, &mut self.http_client, &self.server_url) {
+                    Ok(hashes) => hashes,
+                    Err(e) => {
+                        println!("readdir: could not list {}: {}", hash, e);
+                        reply.error(e.errno());
+                        return;
+                    }
+                };
                 println!("readdir: hashes of children: {:?}", hashes_of_children);
+
+                // the listing has to hash back to the directory hash we asked for,
+                // if it doesn't the server has tampered with something and we verify the tree
+                let dir_metadata = self.files.get(&ino).unwrap().metadata_blob.clone();
+                if hash_of_dir(&dir_metadata, &hashes_of_children) != hash {
+                    println!("hash mismatch, verifying tree");
+                    let top_hash = self.hash_of(1).unwrap();
+                    let report = match verify_merkle_hashes(&top_hash, &self.http_client, &self.server_url) {
+                        Ok(report) => report,
+                        Err(e) => {
+                            println!("readdir: could not verify tree: {}", e);
+                            reply.error(e.errno());
+                            return;
+                        }
+                    };
+                    println!("verified {} nodes, {} mismatches", report.checked, report.mismatches.len());
+                    self.verified = report.is_ok();
+                    if !report.is_ok() {
+                        for mismatch in report.mismatches {
+                            println!("readdir: bad node {} (parent {:?})", mismatch.hash, mismatch.parent);
+                        }
+                        reply.error(EIO);
+                        return;
+                    }
+
+                    // a clean tree doesn't make the listing we got right, so ask again
+                    // and only go on if this one does hash back to the directory
+                    hashes_of_children = match api::get_child_hashes(&hash, &mut self.http_client, &self.server_url) {
+                        Ok(hashes) => hashes,
+                        Err(e) => {
+                            println!("readdir: could not list {}: {}", hash, e);
+                            reply.error(e.errno());
+                            return;
+                        }
+                    };
+                    if hash_of_dir(&dir_metadata, &hashes_of_children) != hash {
+                        println!("readdir: listing of {} still does not match", hash);
+                        reply.error(EIO);
+                        return;
+                    }
+                }
+
+                // the children (and extra hard links) we already have, by their current hash
+                let cached : HashMap<String, (u64, FileType, String)> = self.files.values()
+                    .chain(self.links.values())
+                    .filter(|f| f.xattr.parent_ino == ino && f.xattr.attr.ino != ino)
+                    .map(|f| (f.hash.clone(), (f.xattr.attr.ino, f.xattr.attr.kind, f.xattr.file_name.clone())))
+                    .collect();
                 for (i, child_hash) in hashes_of_children.iter().enumerate() {
-                    match self.files.get(child_hash) {
-                        Some(file) => {
-                            let mut f_clone : File = file.clone();
-                            // check if the file is different; if yes we should verify the tree
-                            let fresh_hash = encrypt_and_hash_file(&mut f_clone, &self.crypto_key);
-                            if fresh_hash != child_hash.clone() { 
-                                println!("hash mismatch, verifying tree");
-                                // FIXME verify tree
-                                // this state should be impossible if the server has not tampered with our data
-                            }
+                    match cached.get(child_hash) {
+                        Some((child_ino, kind, name)) => {
                             {
-                                println!("readdir: adding child: {}", file.xattr.file_name);
+                                println!("readdir: adding child: {}", name);
                                 if i as i64 + 2 >= offset {
-                                    println!("readdir: added child: {}", file.xattr.file_name);
-                                    reply.add(file.xattr.attr.ino, i as i64 + 2, file.xattr.attr.kind, &file.xattr.file_name);
+                                    println!("readdir: added child: {}", name);
+                                    reply.add(*child_ino, i as i64 + 2, *kind, name);
                                 }
                                 
                             }
@@ -144,21 +983,24 @@ impl Filesystem for Q1FS {
                             // TODO: Should this state really be possible? Consider removing this branch
                             if i as i64 + 2 >= offset {
                                 println!("readdir: added child: {}", child_hash);
-                                let xattr = api::get_xattr(&child_hash, &mut self.http_client, &self.crypto_key, &self.server_url);
-                                let data = api::get_data(&child_hash, &mut self.http_client, &self.crypto_key, &self.server_url);
-                                let ino = xattr.attr.ino;
-                                
-                                reply.add(xattr.attr.ino, i as i64 + 2, xattr.attr.kind, &xattr.file_name);
-                                
-                                let file = File {
-                                    xattr: xattr,
-                                    data: data,
+                                let file = match self.fetch_child(ino, child_hash) {
+                                    Ok((file, _)) => file,
+                                    Err(e) => {
+                                        println!("readdir: could not fetch {}: {}", child_hash, e);
+                                        if let ApiError::Integrity(_) = e {
+                                            self.verified = false;
+                                        }
+                                        reply.error(e.errno());
+                                        return;
+                                    }
                                 };
+                                reply.add(file.xattr.attr.ino, i as i64 + 2, file.xattr.attr.kind, &file.xattr.file_name);
 
-                                self.inodes.insert(file.xattr.file_name.clone(), ino);
-                                self.hashes.insert(ino, child_hash.clone());
-                                
-                                self.files.insert(child_hash.clone(), file);
+                                if let Err(e) = self.cache_node(ino, file) {
+                                    println!("readdir: could not fetch {}: {}", child_hash, e);
+                                    reply.error(e.errno());
+                                    return;
+                                }
 
                             }
                                                         
@@ -168,8 +1010,6 @@ impl Filesystem for Q1FS {
 
                 }
                 reply.ok();
-                // update hash of dir
-                                
             }
             None => {
                 println!("readdir: dir not found");
@@ -178,29 +1018,14 @@ impl Filesystem for Q1FS {
                 return;
             }
         }
-
-        // no time hack
-        let dir_hash = self.hashes.get(&ino).unwrap().clone();
-        let dir_file = self.files.get(&dir_hash.clone()).unwrap().clone();
-        let hashes_of_children = api::get_child_hashes(&dir_hash, &mut self.http_client, &self.server_url);
-        let new_hash = hash_of_dir(&dir_file.xattr, &hashes_of_children, &self.crypto_key);
-        self.hashes.remove(&ino);
-        self.hashes.insert(ino, new_hash.clone());
-        self.files.remove(&dir_hash.clone());
-        self.files.insert(new_hash.clone(), dir_file.clone());
-
-        
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        let hash = self.hashes.get(&ino);
-        match hash {
-            Some(hash) => {
-                // TODO make this function return an option/result and handle it here
-                //let xattr = api::get_xattr(&hash.to_string(), &mut self.http_client, &self.crypto_key, &self.server_url);
-                println!("getattr: {} {:?}", ino, hash);
-                let xattr = &self.files.get(hash).unwrap().xattr;
-                reply.attr(&TTL, &xattr.attr.clone());
+        self.commit_expired();
+        match self.attr_of(ino) {
+            Some(attr) => {
+                println!("getattr: {}", ino);
+                reply.attr(&TTL, &attr);
             }
             None => {
                 // impossible state
@@ -212,12 +1037,30 @@ impl Filesystem for Q1FS {
     
     fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: u32, reply: ReplyOpen) {
         println!("open: {}", _ino);
-        // should check flags + perms
+        self.commit_expired();
+        if let Err(e) = self.check_access(_req, _ino, open_mask(_flags)) {
+            reply.error(e);
+            return;
+        }
+        if _ino == STATUS_INO {
+            // its content and length change between getattr and read, direct io makes the
+            // kernel read it as it is now instead of going by a stale size or the page cache
+            reply.opened(0, FOPEN_DIRECT_IO);
+            return;
+        }
 
-        let hash = self.hashes.get(&_ino);
-        match hash {
-            Some(hash) => {
-                reply.opened(0, 0); // all opened instances of this file will share fh 0
+        match self.files.get(&_ino) {
+            Some(file) => {
+                // small files come down right away, the rest once they are actually read or written
+                if file.xattr.attr.size <= self.prefetch_size {
+                    if let Err(e) = self.load_data(_ino) {
+                        println!("open: {}", e);
+                        reply.error(e.errno());
+                        return;
+                    }
+                }
+                let fh = self.open_handle(_ino, open_mask(_flags));
+                reply.opened(fh, 0);
             }
             None => {
                 reply.error(ENOENT);
@@ -228,18 +1071,31 @@ impl Filesystem for Q1FS {
 
     fn create(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _mode: u32, _flags: u32, reply: ReplyCreate) {
         println!("create: {} {:?}", _parent, _name);
+        if let Err(e) = self.check_access(_req, _parent, W_OK | X_OK) {
+            reply.error(e);
+            return;
+        }
+        let name = match _name.to_str() {
+            Some(name) => name.to_string(),
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        if is_reserved(_parent, &name) {
+            reply.error(EEXIST);
+            return;
+        }
 
-        // should check flags + perms
-        // FIXME update parent hashes recursively.
-        let mut parent_hash = self.hashes.get(&_parent).clone();
+        let parent_hash = self.hash_of(_parent);
 
         match parent_hash {
             Some(parent_hash) => {
 
-                let file = File {
+                let mut file = File {
                     xattr: XFileAttr {
                         attr: FileAttr {
-                            ino: self.top_ino + 1, // FIXME
+                            ino: self.new_ino(),
                             size: 0,
                             blocks: 0,
                             atime: SystemTime::now(),
@@ -247,29 +1103,43 @@ impl Filesystem for Q1FS {
                             ctime: SystemTime::now(),
                             crtime: SystemTime::now(),
                             kind: FileType::RegularFile,
-                            perm: 0o777,
-                            nlink: 0,
-                            uid: 0,
-                            gid: 0,
+                            perm: mode_to_perm(_mode),
+                            nlink: 1,
+                            uid: _req.uid(),
+                            gid: _req.gid(),
                             rdev: 0,
                             flags: 0,
                         },
-                        file_name: _name.to_str().unwrap().to_string(),
+                        file_name: name,
                         parent_ino: _parent,
+                        link_target: None,
+                        hard_link: false,
+                        xattrs: BTreeMap::new(),
                     },
                     data: Vec::new(),
+                    metadata_blob: Vec::new(),
+                    hash: String::new(),
+                    loaded: true,
                 };
 
-                let hash = encrypt_and_hash_file(&mut file.clone(), &self.crypto_key);
+                let sealed = seal_file(&file, &self.crypto_key);
+                let hash = hash_of_file(&sealed.metadata, sealed.data.as_ref());
+                file.metadata_blob = sealed.metadata.clone();
 
-                api::create(&file, &parent_hash.clone(), &mut self.http_client, &self.crypto_key, &self.server_url);
+                if let Err(e) = self.insert_node(&sealed, &hash, &parent_hash) {
+                    println!("create: {}", e);
+                    reply.error(e.errno());
+                    return;
+                }
                 
-                self.inodes.insert(file.xattr.file_name.clone(), self.top_ino + 1);
-                self.hashes.insert(self.top_ino + 1, hash.clone());
-                self.files.insert(hash.clone(), file.clone());
-                self.top_ino += 1;
-                self.update_parent_hashes(&file);
-                reply.created(&TTL, &file.xattr.attr, 0, 0, 0);
+                self.missing.remove(&(_parent, file.xattr.file_name.clone()));
+                file.hash = hash;
+                self.entries.insert((_parent, file.xattr.file_name.clone()), file.xattr.attr.ino);
+                self.files.insert(file.xattr.attr.ino, file.clone());
+                // a new file's mode only applies from the next open on, this one
+                // gets whatever access the flags ask for
+                let fh = self.open_handle(file.xattr.attr.ino, open_mask(_flags));
+                reply.created(&TTL, &file.xattr.attr, 0, fh, 0);
             }
             None => {
                 reply.error(ENOENT);
@@ -278,97 +1148,128 @@ impl Filesystem for Q1FS {
     }
 
     fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
-        println!("lookup: {} {}", _parent, _name.to_str().unwrap());
-        let ino = self.inodes.get(_name.to_str().unwrap());
-        match ino {
-            Some(ino) => {
-                let hash = self.hashes.get(ino).unwrap();
-                let xattr = self.files.get(hash).unwrap().xattr.clone();
-                reply.entry(&TTL, &xattr.attr, 0);
+        println!("lookup: {} {:?}", _parent, _name);
+        let name = match _name.to_str() {
+            Some(name) => name,
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        self.commit_expired();
+        if is_reserved(_parent, name) {
+            reply.entry(&TTL, &self.control_attr(CONTROL_DIR_INO), 0);
+            return;
+        }
+        if _parent == CONTROL_DIR_INO {
+            match name {
+                STATUS_FILE => reply.entry(&TTL, &self.control_attr(STATUS_INO), 0),
+                _ => reply.error(ENOENT),
+            }
+            return;
+        }
+        match self.find_child(_parent, name) {
+            Some((ino, _)) => {
+                self.cache_hits += 1;
+                reply.entry(&TTL, &self.attr_of(ino).unwrap(), 0);
             }
             None => {
-                println!("lookup: downloading xattr for {}", _name.to_str().unwrap());
-                let parent_hash = self.hashes.get(&_parent);
-                println!("parent: {:?}", _parent);
-                let child_hashes = api::get_child_hashes(parent_hash.unwrap(), &mut self.http_client, &self.server_url);
-                for hash in child_hashes {
-                    let xattr = api::get_xattr(&hash, &mut self.http_client, &self.crypto_key, &self.server_url);
-                    if xattr.file_name == _name.to_str().unwrap() {
-                        reply.entry(&TTL, &xattr.attr, 0);
+                // a recent miss is answered without asking the server again
+                let key = (_parent, name.to_string());
+                if let Some(missed) = self.missing.get(&key) {
+                    if missed.elapsed() < NEGATIVE_TTL {
+                        self.cache_hits += 1;
+                        reply.error(ENOENT);
                         return;
                     }
+                    self.missing.remove(&key);
                 }
-                println!("lookup: not found");
-                // create file
-                let parent_hash = self.hashes.get(&_parent);
-                match parent_hash {
-                    Some(parent_hash) => {
-                        let file = File {
-                            xattr: XFileAttr {
-                                attr: FileAttr {
-                                    ino: self.top_ino + 1, // FIXME
-                                    size: 0,
-                                    blocks: 0,
-                                    atime: SystemTime::now(),
-                                    mtime: SystemTime::now(),
-                                    ctime: SystemTime::now(),
-                                    crtime: SystemTime::now(),
-                                    kind: FileType::RegularFile,
-                                    perm: 0o777,
-                                    nlink: 0,
-                                    uid: 0,
-                                    gid: 0,
-                                    rdev: 0,
-                                    flags: 0,
-                                },
-                                file_name: _name.to_str().unwrap().to_string(),
-                                parent_ino: _parent,
-                            },
-                            data: Vec::new(),
-                        };
-
-                        let hash = encrypt_and_hash_file(&mut file.clone(), &self.crypto_key);
-                        api::create(&file, &parent_hash.clone(), &mut self.http_client, &self.crypto_key, &self.server_url);
-                        self.inodes.insert(file.xattr.file_name.clone(), self.top_ino + 1);
-                        self.hashes.insert(self.top_ino + 1, hash.clone());
-                        self.files.insert(hash.clone(), file.clone());
-                        self.update_parent_hashes(&file);
-                        reply.entry(&TTL, &file.xattr.attr, 0);
-                    }
+
+                self.cache_misses += 1;
+                println!("lookup: listing the parent for {}", name);
+                let parent_hash = match self.hash_of(_parent) {
+                    Some(hash) => hash,
                     None => {
                         reply.error(ENOENT);
+                        return;
+                    }
+                };
+                println!("parent: {:?}", _parent);
+                let child_hashes = match api::get_child_hashes(&parent_hash, &mut self.http_client, &self.server_url) {
+                    Ok(hashes) => hashes,
+                    Err(e) => {
+                        println!("lookup: could not list {}: {}", _parent, e);
+                        reply.error(e.errno());
+                        return;
                     }
+                };
+                // same check as readdir: the listing has to hash back to the parent
+                let parent_metadata = self.files.get(&_parent).unwrap().metadata_blob.clone();
+                if hash_of_dir(&parent_metadata, &child_hashes) != parent_hash {
+                    println!("lookup: listing of {} does not match its hash", _parent);
+                    self.verified = false;
+                    reply.error(EIO);
+                    return;
                 }
 
-                /*
-                // recursively check the parent directory for the file
-                
-                // truncate the last item in the path
-                let mut pathbuf = PathBuf::from(_name);
-                while pathbuf.pop() {
-                    let parent_ino = self.inodes.get(pathbuf.to_str().unwrap());
-                    match parent_ino {
-                        Some(ino) => {
-                            
-                        }
-                        None => {
-
+                // cache every child we didn't have yet, the next lookups in here are then answered locally
+                let cached : HashSet<String> = self.files.values()
+                    .chain(self.links.values())
+                    .filter(|f| f.xattr.parent_ino == _parent)
+                    .map(|f| f.hash.clone())
+                    .collect();
+                for hash in child_hashes.iter().filter(|hash| !cached.contains(*hash)) {
+                    let fetched = self.fetch_child(_parent, hash).and_then(|(file, _)| self.cache_node(_parent, file));
+                    if let Err(e) = fetched {
+                        println!("lookup: could not fetch {}: {}", hash, e);
+                        if let ApiError::Integrity(_) = e {
+                            self.verified = false;
                         }
+                        reply.error(e.errno());
+                        return;
                     }
                 }
-                */
-            }
-        }
-    }
+                if let Some(&ino) = self.entries.get(&key) {
+                    match self.attr_of(ino) {
+                        Some(attr) => reply.entry(&TTL, &attr, 0),
+                        // a hard link to a file we haven't seen
+                        None => reply.error(ENOENT),
+                    }
+                    return;
+                }
+                println!("lookup: not found");
+                // drop the misses that have expired, or every name ever missed stays here
+                self.missing.retain(|_, missed| missed.elapsed() < NEGATIVE_TTL);
+                self.missing.insert(key, Instant::now());
+                reply.error(ENOENT);
+            }
+        }
+    }
 
     fn read(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _size: u32, reply: ReplyData) {
         println!("read: {} {} {} {}", _ino, _fh, _offset, _size);
-        // check file permissions (TODO)
+        self.commit_expired();
+        // open has checked access to it, and it has no handle
+        if _ino == STATUS_INO {
+            let status = self.status();
+            let start = (_offset as usize).min(status.len());
+            let end = (start + _size as usize).min(status.len());
+            reply.data(&status[start..end]);
+            return;
+        }
+        if let Err(e) = self.check_handle(_fh, _ino, R_OK) {
+            reply.error(e);
+            return;
+        }
 
-        let hash = self.hashes.get(&_ino);
-        match hash {
-            Some(hash) => {
-                let file = self.files.get(hash).unwrap();
+        // the data may not have been downloaded yet
+        if let Err(e) = self.load_data(_ino) {
+            println!("read: {}", e);
+            reply.error(e.errno());
+            return;
+        }
+        match self.files.get(&_ino) {
+            Some(file) => {
                 if _offset > file.data.len() as i64 {
                     reply.error(0);
                     return;
@@ -388,12 +1289,25 @@ impl Filesystem for Q1FS {
 
     fn write(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _data: &[u8], _flags: u32, reply: ReplyWrite) {
         println!("write: {} {} {} {:?}", _ino, _fh, _offset, _data);
-        // check file permissions (TODO)
-        let hash = self.hashes.get(&_ino);
-        match hash {
-            Some(hash) => {
-                let hash = hash.clone();
-                let mut file = self.files.get_mut(&hash.clone()).unwrap().clone();
+        self.commit_expired();
+        // the handle has to be there before the data is touched, or the write would leave
+        // dirty data behind that no handle ever commits
+        if let Err(e) = self.check_handle(_fh, _ino, W_OK) {
+            reply.error(e);
+            return;
+        }
+        if _offset as u64 + _data.len() as u64 > MAX_FILE_SIZE {
+            reply.error(EFBIG);
+            return;
+        }
+        // the data may not have been downloaded yet
+        if let Err(e) = self.load_data(_ino) {
+            println!("write: {}", e);
+            reply.error(e.errno());
+            return;
+        }
+        match self.files.get_mut(&_ino) {
+            Some(file) => {
                 if _offset > file.data.len() as i64 {
                     reply.error(0);
                     return;
@@ -413,15 +1327,10 @@ impl Filesystem for Q1FS {
                 // update the file's xattr
                 file.xattr.attr.size = flen as u64;
                 file.xattr.attr.blocks = flen as u64 / 4096; // bogus, idk how blocks work
-                
-                api::delete(&hash, &mut self.http_client, &self.server_url);
-                api::create(&file, &hash, &mut self.http_client, &self.crypto_key, &self.server_url);
-                let new_hash = encrypt_and_hash_file(&mut file.clone(), &self.crypto_key);
-                self.hashes.remove(&_ino);
-                self.hashes.insert(_ino, new_hash.clone());
-                self.files.insert(new_hash, file.clone());
-                self.files.remove(&hash);
-                self.update_parent_hashes(&file);
+                file.xattr.attr.mtime = SystemTime::now();
+
+                // buffered until flush/fsync/release, or until the handle goes quiet
+                self.handles.get_mut(&_fh).unwrap().last_write = Some(Instant::now());
                 reply.written(_data.len() as u32);
                 
             }
@@ -433,137 +1342,881 @@ impl Filesystem for Q1FS {
 
     fn setattr(&mut self, _req: &Request<'_>, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<SystemTime>, _mtime: Option<SystemTime>, _fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>, reply: ReplyAttr) {
         println!("setattr: {} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}", _ino, _mode, _uid, _gid, _size, _atime, _mtime, _fh, _crtime, _chgtime, _bkuptime, _flags);
-        let hash = self.hashes.get(&_ino);
-        match hash {
-            Some(hash) => {
+        // growing a file means allocating all of it, refuse before downloading anything
+        if _size.map_or(false, |size| size > MAX_FILE_SIZE) {
+            reply.error(EFBIG);
+            return;
+        }
+        // a new size is the only change that needs the data, which may not have been downloaded yet
+        if _size.is_some() {
+            if let Err(e) = self.load_data(_ino) {
+                println!("setattr: {}", e);
+                reply.error(e.errno());
+                return;
+            }
+        }
+        match self.files.get(&_ino).cloned() {
+            Some(mut file) => {
                 // file exists
-                let mut new_hash = "".to_string();
-                let mut file_clone : Option<File> = None;
-                {
-                    let file = self.files.get_mut(hash).unwrap();
-                    let mut attr = file.xattr.attr.clone();
-
-                    // set all the attributes that are Some
-                    if let Some(mode) = _mode {
-                        // attr.mode = mode; doesn't exist?
-                    }
-                    if let Some(uid) = _uid {
-                        attr.uid = uid;
-                    }
-                    if let Some(gid) = _gid {
-                        attr.gid = gid;
-                    }
-                    if let Some(size) = _size {
-                        attr.size = size;
-                    }
-                    if let Some(atime) = _atime {
-                        attr.atime = atime;
+                let mut attr = file.xattr.attr.clone();
+
+                // chmod and chown are for the owner, and only root can give a file away
+                // or hand it to a group it isn't in itself
+                let uid = _req.uid();
+                let is_owner = uid == 0 || uid == attr.uid;
+                if _mode.is_some() && !is_owner {
+                    reply.error(EPERM);
+                    return;
+                }
+                if _uid.map_or(false, |new_uid| new_uid != attr.uid) && uid != 0 {
+                    reply.error(EPERM);
+                    return;
+                }
+                if _gid.map_or(false, |new_gid| new_gid != attr.gid) && !(uid == 0 || (is_owner && _gid == Some(_req.gid()))) {
+                    reply.error(EPERM);
+                    return;
+                }
+                // truncating is writing, so is touching someone else's file. ftruncate
+                // goes by the access its handle was opened with instead
+                if let (Some(_), Some(fh)) = (_size, _fh) {
+                    if let Err(e) = self.check_handle(fh, _ino, W_OK) {
+                        reply.error(e);
+                        return;
                     }
-                    if let Some(mtime) = _mtime {
-                        attr.mtime = mtime;
+                }
+                let needs_write = (_size.is_some() && _fh.is_none()) || ((_atime.is_some() || _mtime.is_some()) && !is_owner);
+                if needs_write && !may_access(&attr, uid, _req.gid(), W_OK) {
+                    reply.error(EACCES);
+                    return;
+                }
+
+                // set all the attributes that are Some
+                if let Some(mode) = _mode {
+                    attr.perm = mode_to_perm(mode);
+                    attr.ctime = SystemTime::now();
+                }
+                if let Some(uid) = _uid {
+                    attr.uid = uid;
+                    attr.ctime = SystemTime::now();
+                }
+                if let Some(gid) = _gid {
+                    attr.gid = gid;
+                    attr.ctime = SystemTime::now();
+                }
+                if let Some(size) = _size {
+                    if attr.kind == FileType::Directory {
+                        reply.error(EISDIR);
+                        return;
                     }
-                    if let Some(fh) = _fh {
-                        // attr.fh = fh; doesn't exist ?
+                    // shrinking drops the tail, growing pads with zeroes
+                    file.data.resize(size as usize, 0);
+                    attr.size = size;
+                    attr.blocks = size / 4096;
+                    attr.mtime = SystemTime::now();
+                }
+                if let Some(atime) = _atime {
+                    attr.atime = atime;
+                }
+                if let Some(mtime) = _mtime {
+                    attr.mtime = mtime;
+                }
+                if let Some(fh) = _fh {
+                    // attr.fh = fh; doesn't exist ?
+                }
+                if let Some(crtime) = _crtime {
+                    attr.crtime = crtime;
+                }
+                if let Some(chgtime) = _chgtime {
+                    // attr.chgtime = chgtime; doesn't exist?
+                }
+                if let Some(bkuptime) = _bkuptime {
+                    // attr.bkuptime = bkuptime; doesn't exist?
+                }
+                if let Some(flags) = _flags {
+                    attr.flags = flags;
+                }
+
+                file.xattr.attr = attr;
+                // only a new size touches the data, anything else is just new metadata
+                // which works the same for files and directories
+                let result = match _size {
+                    Some(_) => self.reupload(_ino, &mut file).map(|_| ()),
+                    None => self.move_node(_ino, &mut file, None),
+                };
+                if let Err(e) = result {
+                    println!("setattr: {}", e);
+                    reply.error(e.errno());
+                    return;
+                }
+                // truncate(2) loads the data without opening the file
+                self.forget_closed(_ino);
+                reply.attr(&TTL, &self.attr_of(_ino).unwrap());
+            }
+            None => {
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    fn mkdir(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _mode: u32, reply: ReplyEntry) {
+        println!("mkdir: {} {:?} {}", _parent, _name, _mode);
+        if let Err(e) = self.check_access(_req, _parent, W_OK | X_OK) {
+            reply.error(e);
+            return;
+        }
+        let name = match _name.to_str() {
+            Some(name) => name.to_string(),
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        if is_reserved(_parent, &name) {
+            reply.error(EEXIST);
+            return;
+        }
+        let parent = self.hash_of(_parent);
+        match parent {
+            Some(parent_hash) => {
+                if self.files.get(&_parent).unwrap().xattr.attr.kind != FileType::Directory {
+                    reply.error(ENOTDIR);
+                    return;
+                }
+                if self.find_child(_parent, &name).is_some() {
+                    reply.error(EEXIST);
+                    return;
+                }
+
+                let ino = self.new_ino();
+                let mut dir = File {
+                    xattr: XFileAttr {
+                        attr: FileAttr {
+                            ino: ino,
+                            size: 0,
+                            blocks: 0,
+                            atime: SystemTime::now(),
+                            mtime: SystemTime::now(),
+                            ctime: SystemTime::now(),
+                            crtime: SystemTime::now(),
+                            kind: FileType::Directory,
+                            perm: mode_to_perm(_mode),
+                            nlink: 2,
+                            uid: _req.uid(),
+                            gid: _req.gid(),
+                            rdev: 0,
+                            flags: 0,
+                        },
+                        file_name: name,
+                        parent_ino: _parent,
+                        link_target: None,
+                        hard_link: false,
+                        xattrs: BTreeMap::new(),
+                    },
+                    data: Vec::new(),
+                    metadata_blob: Vec::new(),
+                    hash: String::new(),
+                    loaded: true,
+                };
+                // a new directory has no children, so its hash only depends on its metadata
+                dir.metadata_blob = seal_metadata(&dir.xattr, &self.crypto_key);
+                let hash = hash_of_dir(&dir.metadata_blob, &Vec::new());
+
+                let response = match api::mkdir(&dir.metadata_blob, Some(parent_hash.clone()), &mut self.http_client, &self.server_url) {
+                    Ok(response) => response,
+                    Err(e) => {
+                        println!("mkdir: {}", e);
+                        reply.error(e.errno());
+                        return;
                     }
-                    if let Some(crtime) = _crtime {
-                        attr.crtime = crtime;
+                };
+                if let Err(e) = self.apply_update(response, vec![(parent_hash, TreeChange::Insert(hash.clone()))]) {
+                    println!("mkdir: {}", e);
+                    reply.error(e.errno());
+                    return;
+                }
+
+                self.missing.remove(&(_parent, dir.xattr.file_name.clone()));
+                self.entries.insert((_parent, dir.xattr.file_name.clone()), ino);
+                dir.hash = hash;
+                self.files.insert(ino, dir.clone());
+                reply.entry(&TTL, &dir.xattr.attr, 0);
+            }
+            None => {
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+        println!("unlink: {} {:?}", _parent, _name);
+        if let Err(e) = self.check_access(_req, _parent, W_OK | X_OK) {
+            reply.error(e);
+            return;
+        }
+        let name = match _name.to_str() {
+            Some(name) => name,
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        match self.find_child(_parent, name) {
+            Some((ino, _)) => {
+                if self.files.get(&ino).unwrap().xattr.attr.kind == FileType::Directory {
+                    reply.error(EISDIR);
+                    return;
+                }
+                if let Err(e) = self.unlink_entry(_parent, name) {
+                    println!("unlink: {}", e);
+                    reply.error(e.errno());
+                    return;
+                }
+                reply.ok();
+            }
+            None => {
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+        println!("rmdir: {} {:?}", _parent, _name);
+        if let Err(e) = self.check_access(_req, _parent, W_OK | X_OK) {
+            reply.error(e);
+            return;
+        }
+        let name = match _name.to_str() {
+            Some(name) => name,
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        match self.find_child(_parent, name) {
+            Some((ino, hash)) => {
+                if self.files.get(&ino).unwrap().xattr.attr.kind != FileType::Directory {
+                    reply.error(ENOTDIR);
+                    return;
+                }
+                // the server listing is authoritative, we may not have seen every child
+                match api::get_child_hashes(&hash, &self.http_client, &self.server_url) {
+                    Ok(children) if !children.is_empty() => {
+                        reply.error(ENOTEMPTY);
+                        return;
                     }
-                    if let Some(chgtime) = _chgtime {
-                        // attr.chgtime = chgtime; doesn't exist?
+                    Ok(_) => {}
+                    Err(e) => {
+                        println!("rmdir: could not list {}: {}", hash, e);
+                        reply.error(e.errno());
+                        return;
                     }
-                    if let Some(bkuptime) = _bkuptime {
-                        // attr.bkuptime = bkuptime; doesn't exist?
+                }
+                if let Err(e) = self.remove_node(ino) {
+                    println!("rmdir: {}", e);
+                    reply.error(e.errno());
+                    return;
+                }
+                reply.ok();
+            }
+            None => {
+                reply.error(ENOENT);
+            }
+        }
+    }
+
+    fn rename(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, reply: ReplyEmpty) {
+        println!("rename: {} {:?} -> {} {:?}", _parent, _name, _newparent, _newname);
+        // this fuse abi doesn't pass renameat2 flags on, the kernel turns down
+        // RENAME_NOREPLACE/RENAME_EXCHANGE itself, so all we see is plain rename(2)
+        for dir in [_parent, _newparent].iter() {
+            if let Err(e) = self.check_access(_req, *dir, W_OK | X_OK) {
+                reply.error(e);
+                return;
+            }
+        }
+        let (name, newname) = match (_name.to_str(), _newname.to_str()) {
+            (Some(name), Some(newname)) => (name.to_string(), newname.to_string()),
+            _ => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        if is_reserved(_newparent, &newname) {
+            reply.error(EEXIST);
+            return;
+        }
+
+        let (ino, _) = match self.find_child(_parent, &name) {
+            Some(child) => child,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        match self.files.get(&_newparent) {
+            Some(new_parent) if new_parent.xattr.attr.kind != FileType::Directory => {
+                reply.error(ENOTDIR);
+                return;
+            }
+            Some(_) => {}
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        }
+        if _parent == _newparent && name == newname {
+            reply.ok();
+            return;
+        }
+
+        let file = self.files.get(&ino).unwrap().clone();
+        let is_dir = file.xattr.attr.kind == FileType::Directory;
+        // a directory can't end up inside itself
+        if is_dir && self.is_ancestor(ino, _newparent) {
+            reply.error(EINVAL);
+            return;
+        }
+
+        // an existing target is replaced, as long as it is the same kind of thing
+        // and, for directories, empty
+        let target = self.find_child(_newparent, &newname);
+        if let Some((target_ino, target_hash)) = &target {
+            // both names already belong to the same file, rename(2) leaves them be
+            if *target_ino == ino {
+                reply.ok();
+                return;
+            }
+            let target_is_dir = self.files.get(target_ino).unwrap().xattr.attr.kind == FileType::Directory;
+            if is_dir && !target_is_dir {
+                reply.error(ENOTDIR);
+                return;
+            }
+            if !is_dir && target_is_dir {
+                reply.error(EISDIR);
+                return;
+            }
+            if target_is_dir {
+                match api::get_child_hashes(target_hash, &self.http_client, &self.server_url) {
+                    Ok(children) if !children.is_empty() => {
+                        reply.error(ENOTEMPTY);
+                        return;
                     }
-                    if let Some(flags) = _flags {
-                        attr.flags = flags;
+                    Ok(_) => {}
+                    Err(e) => {
+                        println!("rename: could not list {}: {}", target_hash, e);
+                        reply.error(e.errno());
+                        return;
                     }
+                }
+            }
+        }
 
-                    let xattr = XFileAttr {
-                        attr: attr,
-                        file_name: file.xattr.file_name.clone(),
-                        parent_ino: file.xattr.parent_ino.clone(),
-                    };
-                    // TODO hack wasn't actually needed, refactor
-                    (*file).xattr = xattr;
-                    // delete the old file
-                    api::delete(&hash, &mut self.http_client, &self.server_url);
-                    // create the new file
-                    api::create(&file, &hash, &mut self.http_client, &self.crypto_key, &self.server_url);
-                    new_hash = encrypt_and_hash_file(&mut file.clone(), &self.crypto_key);
-
-                    file_clone = Some(file.clone());
-                    
-                    
-                    reply.attr(&TTL, &attr);
+        // A target with other names left only loses this name. When the name is an extra
+        // hard link its node is replaced in the same mutation as any other target would be
+        // and the file lives on under its remaining names. The name holding the data first
+        // hands the data over to one of the others (see unlink_entry), which frees the name
+        // for a plain move; the file keeps its data and all of its other names either way
+        let new_key = (_newparent, newname.clone());
+        let target_link = self.links.get(&new_key).map(|link| link.hash.clone());
+        let (target, target_hash) = match target {
+            Some(_) if target_link.is_some() => (None, target_link.clone()),
+            Some((target_ino, _)) if self.attr_of(target_ino).unwrap().nlink > 1 && !is_dir => {
+                if let Err(e) = self.unlink_entry(_newparent, &newname) {
+                    println!("rename: {}", e);
+                    reply.error(e.errno());
+                    return;
                 }
-                //println!("old hash: {} new hash: {} ino: {}", hash, new_hash, _ino);
-                self.files.remove(hash);
-                let second_file_clone = file_clone.unwrap();
-                self.files.insert(new_hash.clone(), second_file_clone.clone());
-                
-                               
-                self.hashes.insert(_ino, new_hash.clone());
-                self.update_parent_hashes(&second_file_clone);
-                
-                
-                
+                (None, None)
+            }
+            Some((target_ino, target_hash)) => (Some(target_ino), Some(target_hash)),
+            None => (None, None),
+        };
+        // a replaced target that is still open stays readable, see remove_node
+        if let Some(target_ino) = target {
+            if self.handles.values().any(|handle| handle.ino == target_ino) {
+                if let Err(e) = self.load_data(target_ino) {
+                    println!("rename: {}", e);
+                    reply.error(e.errno());
+                    return;
+                }
+            }
+        }
+
+        let source_is_link = self.links.contains_key(&(_parent, name.clone()));
+        let result = if source_is_link {
+            self.move_link(_parent, &name, _newparent, &newname, target_hash)
+        }
+        else {
+            let mut moved = file.clone();
+            moved.xattr.file_name = newname;
+            moved.xattr.parent_ino = _newparent;
+            moved.xattr.attr.ctime = SystemTime::now();
+            self.move_node(ino, &mut moved, target_hash)
+        };
+        if let Err(e) = result {
+            println!("rename: {}", e);
+            reply.error(e.errno());
+            return;
+        }
+        // the replaced target had no other names, so it is gone for good. A replaced
+        // link is just a name less, unless the source link has already taken its place
+        if let Some(target_ino) = target {
+            self.drop_node(target_ino);
+        }
+        if target_link.is_some() && !source_is_link {
+            self.links.remove(&new_key);
+        }
+        reply.ok();
+    }
+
+    fn link(&mut self, _req: &Request<'_>, _ino: u64, _newparent: u64, _newname: &OsStr, reply: ReplyEntry) {
+        println!("link: {} -> {} {:?}", _ino, _newparent, _newname);
+        if let Err(e) = self.check_access(_req, _newparent, W_OK | X_OK) {
+            reply.error(e);
+            return;
+        }
+        let name = match _newname.to_str() {
+            Some(name) => name.to_string(),
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        if is_reserved(_newparent, &name) {
+            reply.error(EEXIST);
+            return;
+        }
+        let file = match self.files.get(&_ino) {
+            Some(file) => file.clone(),
+            None => {
+                reply.error(ENOENT);
+                return;
             }
+        };
+        if file.xattr.attr.kind == FileType::Directory {
+            reply.error(EPERM);
+            return;
+        }
+        let parent_hash = match self.hash_of(_newparent) {
+            Some(hash) => hash,
             None => {
                 reply.error(ENOENT);
+                return;
             }
+        };
+        if self.files.get(&_newparent).unwrap().xattr.attr.kind != FileType::Directory {
+            reply.error(ENOTDIR);
+            return;
+        }
+        if self.find_child(_newparent, &name).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+
+        // just a name, the attributes stored with it are never looked at
+        let mut link = File {
+            xattr: XFileAttr {
+                attr: file.xattr.attr.clone(),
+                file_name: name,
+                parent_ino: _newparent,
+                link_target: None,
+                hard_link: true,
+                xattrs: BTreeMap::new(),
+            },
+            data: Vec::new(),
+            metadata_blob: Vec::new(),
+            hash: String::new(),
+            loaded: true,
+        };
+        let sealed = seal_file(&link, &self.crypto_key);
+        let hash = hash_of_file(&sealed.metadata, sealed.data.as_ref());
+        link.metadata_blob = sealed.metadata.clone();
+        if let Err(e) = self.insert_node(&sealed, &hash, &parent_hash) {
+            println!("link: {}", e);
+            reply.error(e.errno());
+            return;
         }
+
+        link.hash = hash;
+        let key = (_newparent, link.xattr.file_name.clone());
+        self.missing.remove(&key);
+        self.entries.insert(key.clone(), _ino);
+        self.links.insert(key, link);
+        reply.entry(&TTL, &self.attr_of(_ino).unwrap(), 0);
     }
 
-    fn mkdir(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _mode: u32, reply: ReplyEntry) {
-        println!("mkdir: {} {:?} {}", _parent, _name, _mode);
-        let parent = self.hashes.get(&_parent);
-        match parent {
-            Some(hash) => {
-                
+    fn symlink(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _link: &Path, reply: ReplyEntry) {
+        println!("symlink: {} {:?} -> {:?}", _parent, _name, _link);
+        if let Err(e) = self.check_access(_req, _parent, W_OK | X_OK) {
+            reply.error(e);
+            return;
+        }
+        let name = match _name.to_str() {
+            Some(name) => name.to_string(),
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        if is_reserved(_parent, &name) {
+            reply.error(EEXIST);
+            return;
+        }
+        let parent_hash = match self.hash_of(_parent) {
+            Some(hash) => hash,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        if self.files.get(&_parent).unwrap().xattr.attr.kind != FileType::Directory {
+            reply.error(ENOTDIR);
+            return;
+        }
+        if self.find_child(_parent, &name).is_some() {
+            reply.error(EEXIST);
+            return;
+        }
+        let target = match _link.to_str() {
+            Some(target) => target.to_string(),
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+
+        let ino = self.new_ino();
+        let mut link = File {
+            xattr: XFileAttr {
+                attr: FileAttr {
+                    ino: ino,
+                    size: target.len() as u64,
+                    blocks: 0,
+                    atime: SystemTime::now(),
+                    mtime: SystemTime::now(),
+                    ctime: SystemTime::now(),
+                    crtime: SystemTime::now(),
+                    kind: FileType::Symlink,
+                    perm: 0o777, // never looked at for symlinks
+                    nlink: 1,
+                    uid: _req.uid(),
+                    gid: _req.gid(),
+                    rdev: 0,
+                    flags: 0,
+                },
+                file_name: name,
+                parent_ino: _parent,
+                link_target: Some(target),
+                hard_link: false,
+                xattrs: BTreeMap::new(),
+            },
+            data: Vec::new(),
+            metadata_blob: Vec::new(),
+            hash: String::new(),
+            loaded: true,
+        };
+        // a link is a leaf like any file, just without data
+        let sealed = seal_file(&link, &self.crypto_key);
+        let hash = hash_of_file(&sealed.metadata, sealed.data.as_ref());
+        link.metadata_blob = sealed.metadata.clone();
+        if let Err(e) = self.insert_node(&sealed, &hash, &parent_hash) {
+            println!("symlink: {}", e);
+            reply.error(e.errno());
+            return;
+        }
+
+        link.hash = hash;
+        self.missing.remove(&(_parent, link.xattr.file_name.clone()));
+        self.entries.insert((_parent, link.xattr.file_name.clone()), ino);
+        self.files.insert(ino, link.clone());
+        reply.entry(&TTL, &link.xattr.attr, 0);
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyData) {
+        println!("readlink: {}", _ino);
+        match self.files.get(&_ino) {
+            Some(file) => match &file.xattr.link_target {
+                Some(target) => reply.data(target.as_bytes()),
+                None => reply.error(EINVAL),
+            },
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn setxattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32, reply: ReplyEmpty) {
+        println!("setxattr: {} {:?} ({} bytes)", _ino, _name, _value.len());
+        let name = match _name.to_str() {
+            Some(name) => name,
+            None => {
+                reply.error(EINVAL);
+                return;
             }
+        }.to_string();
+        if !name.starts_with("user.") {
+            reply.error(ENOTSUP);
+            return;
+        }
+        if name.starts_with(XATTR_VIRTUAL_PREFIX) {
+            reply.error(EPERM);
+            return;
+        }
+        if name.len() > XATTR_NAME_MAX {
+            reply.error(ERANGE);
+            return;
+        }
+        if _value.len() > XATTR_VALUE_MAX {
+            reply.error(E2BIG);
+            return;
+        }
+        if let Err(e) = self.check_access(_req, _ino, W_OK) {
+            reply.error(e);
+            return;
+        }
+        let mut file = match self.files.get(&_ino) {
+            Some(file) => file.clone(),
             None => {
                 reply.error(ENOENT);
+                return;
+            }
+        };
+        let exists = file.xattr.xattrs.contains_key(&name);
+        if exists && _flags as i32 & XATTR_CREATE != 0 {
+            reply.error(EEXIST);
+            return;
+        }
+        if !exists && _flags as i32 & XATTR_REPLACE != 0 {
+            reply.error(ENODATA);
+            return;
+        }
+        file.xattr.xattrs.insert(name, base64::encode(_value));
+        let total : usize = file.xattr.xattrs.iter().map(|(name, value)| name.len() + value.len()).sum();
+        if total > XATTR_TOTAL_MAX {
+            reply.error(ENOSPC);
+            return;
+        }
+
+        file.xattr.attr.ctime = SystemTime::now();
+        if let Err(e) = self.move_node(_ino, &mut file, None) {
+            println!("setxattr: {}", e);
+            reply.error(e.errno());
+            return;
+        }
+        reply.ok();
+    }
+
+    fn getxattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
+        println!("getxattr: {} {:?} {}", _ino, _name, _size);
+        if let Err(e) = self.check_access(_req, _ino, R_OK) {
+            reply.error(e);
+            return;
+        }
+        let name = match _name.to_str() {
+            Some(name) => name,
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        let value = match self.files.get(&_ino) {
+            Some(file) if name == XATTR_HASH => Some(file.hash.clone().into_bytes()),
+            Some(_) if name == XATTR_VERIFIED => Some(self.verified.to_string().into_bytes()),
+            // sealed by us, so it decodes unless something is very wrong
+            Some(file) => file.xattr.xattrs.get(name).and_then(|value| base64::decode(value).ok()),
+            None => None,
+        };
+        let value = match value {
+            Some(value) => value,
+            None => {
+                reply.error(ENODATA);
+                return;
+            }
+        };
+        // a size of 0 asks how big a buffer is needed
+        if _size == 0 {
+            reply.size(value.len() as u32);
+        }
+        else if value.len() > _size as usize {
+            reply.error(ERANGE);
+        }
+        else {
+            reply.data(&value);
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, _ino: u64, _size: u32, reply: ReplyXattr) {
+        println!("listxattr: {} {}", _ino, _size);
+        if let Err(e) = self.check_access(_req, _ino, R_OK) {
+            reply.error(e);
+            return;
+        }
+        // names one after the other, each ending in a nul
+        let mut names = Vec::new();
+        if let Some(file) = self.files.get(&_ino) {
+            let virtual_names = [XATTR_HASH, XATTR_VERIFIED];
+            for name in virtual_names.iter().cloned().chain(file.xattr.xattrs.keys().map(|name| name.as_str())) {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+        }
+        if _size == 0 {
+            reply.size(names.len() as u32);
+        }
+        else if names.len() > _size as usize {
+            reply.error(ERANGE);
+        }
+        else {
+            reply.data(&names);
+        }
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
+        println!("removexattr: {} {:?}", _ino, _name);
+        if let Err(e) = self.check_access(_req, _ino, W_OK) {
+            reply.error(e);
+            return;
+        }
+        let name = match _name.to_str() {
+            Some(name) => name,
+            None => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        if name.starts_with(XATTR_VIRTUAL_PREFIX) {
+            reply.error(EPERM);
+            return;
+        }
+        let mut file = match self.files.get(&_ino) {
+            Some(file) => file.clone(),
+            None => {
+                reply.error(ENODATA);
+                return;
+            }
+        };
+        if file.xattr.xattrs.remove(name).is_none() {
+            reply.error(ENODATA);
+            return;
+        }
+
+        file.xattr.attr.ctime = SystemTime::now();
+        if let Err(e) = self.move_node(_ino, &mut file, None) {
+            println!("removexattr: {}", e);
+            reply.error(e.errno());
+            return;
+        }
+        reply.ok();
+    }
+
+    fn access(&mut self, _req: &Request<'_>, _ino: u64, _mask: u32, reply: ReplyEmpty) {
+        println!("access: {} {:o}", _ino, _mask);
+        // F_OK only asks whether the file is there
+        if let Err(e) = self.check_access(_req, _ino, _mask as i32) {
+            reply.error(e);
+            return;
+        }
+        reply.ok();
+    }
+
+    fn flush(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        println!("flush: {} {}", _ino, _fh);
+        if let Err(e) = self.commit(_fh) {
+            println!("flush: {}", e);
+            reply.error(e.errno());
+            return;
+        }
+        reply.ok();
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        println!("fsync: {} {}", _ino, _fh);
+        if let Err(e) = self.commit(_fh) {
+            println!("fsync: {}", e);
+            reply.error(e.errno());
+            return;
+        }
+        reply.ok();
+    }
+
+    fn release(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+        println!("release: {} {}", _ino, _fh);
+        if let Err(e) = self.commit(_fh) {
+            // keep the handle and its dirty data, commit_expired (or destroy) tries again
+            println!("release: {}", e);
+            if let Some(handle) = self.handles.get_mut(&_fh) {
+                handle.released = true;
+            }
+            reply.error(e.errno());
+            return;
+        }
+        self.handles.remove(&_fh);
+        self.forget_closed(_ino);
+        reply.ok();
+    }
+
+    fn destroy(&mut self, _req: &Request) {
+        // last chance for anything still buffered
+        let handles : Vec<u64> = self.handles.keys().cloned().collect();
+        for fh in handles {
+            if let Err(e) = self.commit(fh) {
+                println!("destroy: could not commit fh {}: {}", fh, e);
             }
         }
     }
 
     fn init(&mut self, _req: &Request) -> Result<(), c_int> { 
         println!("init");
-        // check if root dir exists
-        // FIXME establish implementation
-        
-        let has_root_dir = false;
-        if !has_root_dir {
-            // create root dir
-            let root_dir = File {
-                xattr: XFileAttr {
-                    attr: FileAttr {
-                        ino: 1,
-                        size: 0,
-                        blocks: 0,
-                        atime: SystemTime::now(),
-                        mtime: SystemTime::now(),
-                        ctime: SystemTime::now(),
-                        crtime: SystemTime::now(),
-                        kind: FileType::Directory,
-                        perm: 0o777,
-                        nlink: 0,
-                        uid: 0,
-                        gid: 0,
-                        rdev: 0,
-                        flags: 0,
-                    },
-                    file_name: "root".to_string(),
-                    parent_ino: 1,
-                },
-                data: Vec::new(),
-            };
-            let top_hash = api::new_root(&root_dir.xattr, &mut self.http_client, &self.crypto_key, &self.server_url);
-            self.inodes.insert("".to_string(), 1);
-            self.hashes.insert(1, top_hash.clone()); // FIXME
-            self.files.insert(top_hash, root_dir);
+        // the root has to exist already, see Q1FS::create_vault
+        if let Err(e) = self.load_root() {
+            println!("init: could not load root: {}", e);
+            return Err(e.errno());
         }
-
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // owned by 1000:100
+    fn attr(kind : FileType, perm : u16) -> FileAttr {
+        let mut attr = Q1FS::root_dir(1000, 100).xattr.attr;
+        attr.kind = kind;
+        attr.perm = perm;
+        attr
+    }
+
+    #[test]
+    fn owner_group_and_others_get_their_bits() {
+        let file = attr(FileType::RegularFile, 0o640);
+        assert!(may_access(&file, 1000, 100, R_OK | W_OK));
+        assert!(!may_access(&file, 1000, 100, X_OK));
+        assert!(may_access(&file, 1001, 100, R_OK));
+        assert!(!may_access(&file, 1001, 100, W_OK));
+        assert!(!may_access(&file, 1001, 101, R_OK));
+        // the owner's bits apply even when the group's would allow more
+        let file = attr(FileType::RegularFile, 0o070);
+        assert!(!may_access(&file, 1000, 100, R_OK));
+        assert!(may_access(&file, 1001, 100, R_OK | W_OK | X_OK));
+        // F_OK only asks whether it exists
+        assert!(may_access(&attr(FileType::RegularFile, 0), 1001, 101, 0));
+    }
+
+    #[test]
+    fn root_may_do_anything_but_execute_what_nobody_can() {
+        let file = attr(FileType::RegularFile, 0o600);
+        assert!(may_access(&file, 0, 0, R_OK | W_OK));
+        assert!(!may_access(&file, 0, 0, X_OK));
+        assert!(may_access(&attr(FileType::RegularFile, 0o100), 0, 0, X_OK));
+        assert!(may_access(&attr(FileType::Directory, 0), 0, 0, R_OK | W_OK | X_OK));
+    }
+
+    #[test]
+    fn open_flags_map_to_access_bits() {
+        assert_eq!(open_mask(libc::O_RDONLY as u32), R_OK);
+        assert_eq!(open_mask(O_WRONLY as u32), W_OK);
+        assert_eq!(open_mask(O_RDWR as u32), R_OK | W_OK);
+        assert_eq!(open_mask((libc::O_RDONLY | O_TRUNC) as u32), R_OK | W_OK);
+        assert_eq!(open_mask((O_WRONLY | libc::O_APPEND | libc::O_CREAT) as u32), W_OK);
+    }
+}
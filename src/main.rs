@@ -4,13 +4,85 @@ mod crypto;
 use fuse::mount;
 use std::ffi::OsStr;
 use std::env;
+use std::io::{self, Write};
+use std::process;
 
 fn main() {
+    // dstrombe-crypto --init          sets up the vault, once
+    // dstrombe-crypto <mountpoint>    mounts it
+    let arg = match env::args_os().nth(1) {
+        Some(arg) => arg,
+        None => {
+            eprintln!("usage: dstrombe-crypto --init | dstrombe-crypto <mountpoint>");
+            process::exit(1);
+        }
+    };
+    let vault = vault_id();
+
+    if arg == "--init" {
+        if let Err(e) = fs::Q1FS::create_vault(&read_passphrase(), &vault) {
+            eprintln!("could not initialize vault: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
     println!("Attempting mount");
-    let mountpoint = env::args_os().nth(1).unwrap();
-    let options = ["-o", "rw", "-o", "fsname=hello"]
+    let mountpoint = arg;
+    // the kernel checks permissions too, before a request ever reaches us
+    let options = ["-o", "rw", "-o", "fsname=hello", "-o", "default_permissions"]
         .iter()
         .map(|o| o.as_ref())
         .collect::<Vec<&OsStr>>();
-    fuse::mount(fs::Q1FS::new(), &mountpoint, &options).unwrap();
+    let fs = match fs::Q1FS::new(&read_passphrase(), &vault, prefetch_size()) {
+        Ok(fs) => fs,
+        Err(e) => {
+            eprintln!("could not unlock vault: {}", e);
+            process::exit(1);
+        }
+    };
+    fuse::mount(fs, &mountpoint, &options).unwrap();
+}
+
+// which vault on the server to use: Q1FS_VAULT, or else the user's name
+fn vault_id() -> String {
+    env::var("Q1FS_VAULT")
+        .or_else(|_| env::var("USER"))
+        .unwrap_or("default".to_string())
+}
+
+// files up to this many bytes are downloaded as soon as they are opened,
+// Q1FS_PREFETCH or 64 KiB
+fn prefetch_size() -> u64 {
+    env::var("Q1FS_PREFETCH")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(64 * 1024)
+}
+
+// taken from Q1FS_PASSPHRASE if set, otherwise read from stdin
+fn read_passphrase() -> String {
+    if let Ok(passphrase) = env::var("Q1FS_PASSPHRASE") {
+        return passphrase;
+    }
+    print!("Passphrase: ");
+    io::stdout().flush().unwrap();
+
+    // turn off echo while it is typed, if stdin is a terminal at all
+    let fd = libc::STDIN_FILENO;
+    let mut term : libc::termios = unsafe { std::mem::zeroed() };
+    let is_tty = unsafe { libc::tcgetattr(fd, &mut term) } == 0;
+    if is_tty {
+        let mut silent = term;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) };
+    }
+    let mut passphrase = String::new();
+    let result = io::stdin().read_line(&mut passphrase);
+    if is_tty {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &term) };
+        println!();
+    }
+    result.unwrap();
+    passphrase.trim_end_matches(&['\r', '\n'][..]).to_string()
 }